@@ -1,3 +1,125 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The kernel we take symbols from, set by `just build` on its second pass
+const SYMBOLS_FROM: &str = "KERNEL_SYMBOLS";
+
+// bits of the ELF64 format we need to find the symbol table
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
 fn main() {
     println!("cargo:rerun-if-changed=linker.ld");
+    println!("cargo:rerun-if-env-changed={SYMBOLS_FROM}");
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("ksyms.bin");
+
+    // We cannot know where functions end up before the kernel is linked, so the table comes from
+    // a previous build. The kernel reserves the same amount of space for it either way, which
+    // keeps every function exactly where it was
+    let symbols = match env::var(SYMBOLS_FROM) {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={path}");
+            read_symbols(Path::new(&path)).unwrap_or_else(|err| {
+                println!("cargo:warning=no symbols from {path}: {err}");
+                Vec::new()
+            })
+        }
+        Err(_) => Vec::new(),
+    };
+
+    fs::write(out, encode(&symbols)).unwrap();
+}
+
+struct Symbol {
+    addr: u64,
+    size: u32,
+    name: String,
+}
+
+/// Every function in the symbol table of the ELF at `path`, sorted by address
+fn read_symbols(path: &Path) -> Result<Vec<Symbol>, String> {
+    let elf = fs::read(path).map_err(|err| err.to_string())?;
+
+    if elf.get(..6) != Some(b"\x7fELF\x02\x01") {
+        return Err("not a little endian ELF64 file".into());
+    }
+
+    let shoff = read_u64(&elf, 0x28)? as usize;
+    let shnum = read_u16(&elf, 0x3c)? as usize;
+    let section = |index: usize| shoff + index * SHDR_SIZE;
+
+    let symtab = (0..shnum)
+        .map(section)
+        .find(|&header| read_u32(&elf, header + 0x4) == Ok(SHT_SYMTAB))
+        .ok_or("no symbol table, is the kernel stripped?")?;
+
+    let offset = read_u64(&elf, symtab + 0x18)? as usize;
+    let size = read_u64(&elf, symtab + 0x20)? as usize;
+    // the string table of the symbols is the section in sh_link
+    let strtab = section(read_u32(&elf, symtab + 0x28)? as usize);
+    let strtab = read_u64(&elf, strtab + 0x18)? as usize;
+
+    let mut symbols = Vec::new();
+    for sym in (offset..offset + size).step_by(SYM_SIZE) {
+        let info = *elf.get(sym + 0x4).ok_or("symbol out of bounds")?;
+        let addr = read_u64(&elf, sym + 0x8)?;
+        if info & 0xf != STT_FUNC || addr == 0 {
+            continue;
+        }
+
+        let name = strtab + read_u32(&elf, sym)? as usize;
+        let name = elf[name..].split(|&b| b == 0).next().unwrap_or_default();
+        let name = String::from_utf8_lossy(name);
+
+        symbols.push(Symbol {
+            addr,
+            size: read_u64(&elf, sym + 0x10)? as u32,
+            // the alternate form leaves out the hash at the end
+            name: format!("{:#}", rustc_demangle::demangle(&name)),
+        });
+    }
+
+    symbols.sort_by_key(|symbol| symbol.addr);
+    symbols.dedup_by_key(|symbol| symbol.addr);
+    Ok(symbols)
+}
+
+/// The format kernel::backtrace reads: the number of symbols, then an entry of (address, size,
+/// offset of the name) for each one, then all the names back to back
+fn encode(symbols: &[Symbol]) -> Vec<u8> {
+    let mut table = Vec::new();
+    let mut names: Vec<u8> = Vec::new();
+
+    table.extend((symbols.len() as u64).to_le_bytes());
+    for symbol in symbols {
+        table.extend(symbol.addr.to_le_bytes());
+        table.extend(symbol.size.to_le_bytes());
+        table.extend((names.len() as u32).to_le_bytes());
+        names.extend(symbol.name.as_bytes());
+    }
+
+    table.extend(names);
+    table
+}
+
+fn read_u16(elf: &[u8], at: usize) -> Result<u16, String> {
+    bytes(elf, at).map(u16::from_le_bytes)
+}
+
+fn read_u32(elf: &[u8], at: usize) -> Result<u32, String> {
+    bytes(elf, at).map(u32::from_le_bytes)
+}
+
+fn read_u64(elf: &[u8], at: usize) -> Result<u64, String> {
+    bytes(elf, at).map(u64::from_le_bytes)
+}
+
+fn bytes<const N: usize>(elf: &[u8], at: usize) -> Result<[u8; N], String> {
+    elf.get(at..at + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("read past the end of the file at {at:#x}"))
 }
@@ -79,42 +79,57 @@ impl<const SIZE: usize> BitMap<SIZE> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{PAGE_SIZE, symbols};
+    use crate::PAGE_SIZE;
+    use crate::allocator::FRAME_ALLOC;
+    use crate::vmem::phys_to_virt;
+
+    /// Runs `test` with a scratch page from the frame allocator
+    fn with_page(test: impl FnOnce(usize)) {
+        let page = FRAME_ALLOC.lock().alloc(0).unwrap();
+        test(phys_to_virt(page));
+        FRAME_ALLOC.lock().free(page).unwrap();
+    }
 
     #[test_case]
     fn test_zero() {
-        const N: usize = 1234;
-        let bm = unsafe { BitMap::<N>::zeroed(symbols::HEAP0_TOP) };
+        with_page(|page| {
+            const N: usize = 1234;
+            let bm = unsafe { BitMap::<N>::zeroed(page) };
 
-        for i in 0..N {
-            unsafe { assert_eq!((*bm.inner)[i], 0, "index#{i}") };
-        }
+            for i in 0..N {
+                unsafe { assert_eq!((*bm.inner)[i], 0, "index#{i}") };
+            }
 
-        // and a larger, the "default" PAGE_SIZE amount
-        let bm = unsafe { BitMap::<PAGE_SIZE>::zeroed(symbols::HEAP0_TOP) };
+            // and a larger, the "default" PAGE_SIZE amount
+            let bm = unsafe { BitMap::<PAGE_SIZE>::zeroed(page) };
 
-        for i in 0..PAGE_SIZE {
-            unsafe { assert_eq!((*bm.inner)[i], 0, "index#{i}") };
-        }
+            for i in 0..PAGE_SIZE {
+                unsafe { assert_eq!((*bm.inner)[i], 0, "index#{i}") };
+            }
+        });
     }
 
     #[test_case]
     fn test_get_put() {
-        let mut bm = unsafe { BitMap::<PAGE_SIZE>::zeroed(symbols::HEAP0_TOP) };
+        with_page(|page| {
+            let mut bm = unsafe { BitMap::<PAGE_SIZE>::zeroed(page) };
 
-        // initial state should be all 0s (aka false)
-        assert!(!bm.get(0));
+            // initial state should be all 0s (aka false)
+            assert!(!bm.get(0));
 
-        bm.put(13, true);
-        assert!(bm.get(13));
+            bm.put(13, true);
+            assert!(bm.get(13));
 
-        bm.put(13, false);
-        assert!(!bm.get(13));
+            bm.put(13, false);
+            assert!(!bm.get(13));
+        });
     }
 
     #[test_case]
     fn test_len() {
-        let bm = unsafe { BitMap::<PAGE_SIZE>::zeroed(symbols::HEAP0_TOP) };
-        assert_eq!(bm.len(), PAGE_SIZE * 8);
+        with_page(|page| {
+            let bm = unsafe { BitMap::<PAGE_SIZE>::zeroed(page) };
+            assert_eq!(bm.len(), PAGE_SIZE * 8);
+        });
     }
 }
@@ -1,25 +1,266 @@
-use core::alloc::GlobalAlloc;
+//! The kernel's global allocator, this is what makes `alloc` work
+//!
+//! Small allocations are served from a handful of power-of-two size classes. Each class carves
+//! whole pages from [FRAME_ALLOC] into equally sized objects, and keeps the free ones in a linked
+//! list. Anything larger than the biggest class gets its own range in the lazily mapped [vheap],
+//! or a block of pages straight from the frame allocator if that is not available yet.
+
+use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
-use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+use super::{FRAME_ALLOC, vheap};
 use crate::PAGE_SIZE;
+use crate::sync::IrqMutex;
+use crate::vmem::{phys_to_virt, virt_to_phys};
 
-use super::BitMapAlloc;
-
-static ALLOC_PTR: AtomicPtr<InnerAlloc> = AtomicPtr::new(null_mut());
+/// Object sizes of the size classes, every class is twice the size of the previous one
+pub const CLASS_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+const CLASS_COUNT: usize = CLASS_SIZES.len();
 
-pub struct InnerAlloc;
+static CLASSES: [IrqMutex<SizeClass>; CLASS_COUNT] =
+    [const { IrqMutex::new(SizeClass::empty()) }; CLASS_COUNT];
+static STATS: Stats = Stats::new();
 
-/// global bitmap alloc
+/// global alloc, backed by the frame allocator
 pub struct GBMAlloc;
 
 unsafe impl GlobalAlloc for GBMAlloc {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        log::debug!("{layout:?}");
-        todo!()
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = match class_for(layout) {
+            Some(class) => CLASSES[class].lock().alloc(CLASS_SIZES[class]),
+            None => alloc_large(layout),
+        };
+
+        if !ptr.is_null() {
+            STATS.record_alloc(layout);
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match class_for(layout) {
+            Some(class) => CLASSES[class].lock().free(ptr),
+            None if vheap::contains(ptr as usize) => {
+                vheap::free(ptr as usize, large_pages(layout));
+            }
+            None => {
+                let res = FRAME_ALLOC.lock().free(virt_to_phys(ptr as usize));
+                res.expect("could not free large allocation");
+            }
+        }
+
+        STATS.record_dealloc(layout);
+    }
+}
+
+/// The size class an allocation belongs to, `None` if it needs pages of its own
+fn class_for(layout: Layout) -> Option<usize> {
+    // objects are aligned to their size, so we can satisfy any alignment up to the class size
+    let size = layout.size().max(layout.align());
+    CLASS_SIZES.iter().position(|&class| size <= class)
+}
+
+fn large_pages(layout: Layout) -> usize {
+    layout.size().max(layout.align()).div_ceil(PAGE_SIZE)
+}
+
+fn alloc_large(layout: Layout) -> *mut u8 {
+    let pages = large_pages(layout);
+
+    // the vheap only hands out page aligned ranges
+    if layout.align() <= PAGE_SIZE
+        && let Some(addr) = vheap::alloc(pages)
+    {
+        return addr as *mut u8;
+    }
+
+    // blocks from the frame allocator are aligned to their size, which is always at least as big
+    // as the alignment we were asked for
+    match FRAME_ALLOC.lock().alloc_pages(pages) {
+        Ok(addr) => phys_to_virt(addr) as *mut u8,
+        Err(_) => null_mut(),
+    }
+}
+
+/// Written at the start of every free object in a size class
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct SizeClass {
+    free: *mut FreeObject,
+}
+
+// safety: the objects in the free list are owned by the size class, which is always behind a lock
+unsafe impl Send for SizeClass {}
+
+impl SizeClass {
+    const fn empty() -> Self {
+        Self { free: null_mut() }
+    }
+
+    fn alloc(&mut self, size: usize) -> *mut u8 {
+        if self.free.is_null() && !self.refill(size) {
+            return null_mut();
+        }
+
+        let object = self.free;
+        // safety: the free list only contains valid objects
+        self.free = unsafe { (*object).next };
+        object as *mut u8
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        // safety: the object was handed out by this class, and is at least 16 bytes large
+        unsafe { object.write(FreeObject { next: self.free }) };
+        self.free = object;
+    }
+
+    /// Carve a fresh page into objects. Pages are never handed back to the frame allocator
+    fn refill(&mut self, size: usize) -> bool {
+        let Ok(page) = FRAME_ALLOC.lock().alloc(0) else {
+            return false;
+        };
+        let page = phys_to_virt(page);
+
+        for object in (page..page + PAGE_SIZE).step_by(size).rev() {
+            self.free(object as *mut u8);
+        }
+
+        STATS.class_pages.fetch_add(1, Ordering::Relaxed);
+        true
     }
+}
+
+struct Stats {
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+    class_pages: AtomicUsize,
+    large: AtomicUsize,
+    classes: [AtomicUsize; CLASS_COUNT],
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            class_pages: AtomicUsize::new(0),
+            large: AtomicUsize::new(0),
+            classes: [const { AtomicUsize::new(0) }; CLASS_COUNT],
+        }
+    }
+
+    fn record_alloc(&self, layout: Layout) {
+        let in_use = self.in_use.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        self.peak.fetch_max(in_use, Ordering::Relaxed);
+        self.counter(layout).fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_dealloc(&self, layout: Layout) {
+        self.in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.counter(layout).fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn counter(&self, layout: Layout) -> &AtomicUsize {
+        match class_for(layout) {
+            Some(class) => &self.classes[class],
+            None => &self.large,
+        }
+    }
+}
+
+/// A snapshot of the state of the global allocator
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// bytes currently handed out (as requested, not rounded up to the class size)
+    pub in_use: usize,
+    /// the highest `in_use` has ever been
+    pub peak: usize,
+    /// pages taken from the frame allocator by the size classes
+    pub class_pages: usize,
+    /// number of live allocations that got their own pages
+    pub large: usize,
+    /// number of live allocations in every size class
+    pub classes: [usize; CLASS_COUNT],
+}
+
+impl HeapStats {
+    pub fn pretty_print(&self) {
+        crate::println!(
+            "heap: {} bytes in use, {} bytes peak, {} class pages, {} large allocations",
+            self.in_use,
+            self.peak,
+            self.class_pages,
+            self.large,
+        );
+
+        for (size, count) in CLASS_SIZES.iter().zip(self.classes) {
+            crate::println!("{size:>6} bytes: {count} live");
+        }
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    let load = |value: &AtomicUsize| value.load(Ordering::Relaxed);
+
+    HeapStats {
+        in_use: load(&STATS.in_use),
+        peak: load(&STATS.peak),
+        class_pages: load(&STATS.class_pages),
+        large: load(&STATS.large),
+        classes: STATS.classes.each_ref().map(load),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test_case]
+    fn size_classes() {
+        let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+
+        assert_eq!(class_for(layout(1, 1)), Some(0));
+        assert_eq!(class_for(layout(16, 8)), Some(0));
+        assert_eq!(class_for(layout(17, 8)), Some(1));
+        assert_eq!(class_for(layout(8, 256)), Some(4));
+        assert_eq!(class_for(layout(2048, 8)), Some(CLASS_COUNT - 1));
+        assert_eq!(class_for(layout(2049, 8)), None);
+        assert_eq!(class_for(layout(8, PAGE_SIZE)), None);
+    }
+
+    #[test_case]
+    fn objects_are_reused() {
+        let first = Box::new([0u8; 100]);
+        let addr = &*first as *const _ as usize;
+        drop(first);
+
+        // the free list is LIFO, so we should get the same object back
+        let second = Box::new([1u8; 100]);
+        assert_eq!(&*second as *const _ as usize, addr);
+        assert!(addr.is_multiple_of(128));
+    }
+
+    #[test_case]
+    fn large_allocations() {
+        let before = heap_stats();
+
+        let vec = Vec::<u8>::with_capacity(3 * PAGE_SIZE);
+        assert!((vec.as_ptr() as usize).is_multiple_of(PAGE_SIZE));
+
+        let during = heap_stats();
+        assert_eq!(during.large, before.large + 1);
+        assert!(during.in_use >= before.in_use + 3 * PAGE_SIZE);
+        assert!(during.peak >= during.in_use);
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        todo!()
+        drop(vec);
+        assert_eq!(heap_stats().large, before.large);
     }
 }
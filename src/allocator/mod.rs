@@ -1,15 +1,62 @@
 //! (to be moved)
-//! A simple bitmap allocator
+//! A simple bitmap allocator, the buddy allocator that manages physical memory, the global
+//! allocator built on top of it and the lazily mapped region its large allocations come from
 
 #![allow(unused)]
 
 mod bitmap;
 mod global_impl;
 mod tiered;
+pub mod vheap;
 
 use spin::Mutex;
 
+pub use self::global_impl::{GBMAlloc, HeapStats, heap_stats};
+pub use self::tiered::{BuddySystem, MAX_ORDER, order_for_pages};
 use crate::PAGE_SIZE;
+use crate::pmem::RegionList;
+use crate::sync::IrqMutex;
+use crate::vmem::phys_to_virt;
+
+/// The physical frame allocator, every page the kernel uses (page tables, stacks, ...) is handed
+/// out by this allocator
+pub static FRAME_ALLOC: IrqMutex<BuddySystem> = IrqMutex::new(BuddySystem::empty());
+
+/// Hand the given regions of physical memory over to [FRAME_ALLOC]. The metadata of the allocator
+/// is stored in the first region that is large enough, and is removed from `free`.
+///
+/// # Safety
+/// The memory must be valid, and must not be used by anything else
+pub unsafe fn init_frames(free: &mut RegionList) -> Result<(), AllocatorError> {
+    let start = free
+        .iter()
+        .map(|r| r.start)
+        .min()
+        .ok_or(AllocatorError::OutOfMemory)?;
+    let end = free
+        .iter()
+        .map(|r| r.end)
+        .max()
+        .ok_or(AllocatorError::OutOfMemory)?;
+
+    let meta_size = crate::round_up_by(BuddySystem::metadata_size(end - start), PAGE_SIZE);
+    let meta = free
+        .iter()
+        .find(|r| r.size() >= meta_size)
+        .ok_or(AllocatorError::OutOfMemory)?
+        .start;
+    free.subtract(meta, meta + meta_size);
+
+    // safety: the caller guarantees the regions are ours, and we do not hand the metadata out
+    let mut buddy =
+        unsafe { BuddySystem::init(start, end - start, phys_to_virt(meta) as *mut u8)? };
+    for region in free.iter() {
+        unsafe { buddy.add_region(region.start, region.end) };
+    }
+
+    *FRAME_ALLOC.lock() = buddy;
+    Ok(())
+}
 
 #[derive(Debug)]
 pub struct BitMapAlloc {
@@ -18,8 +65,10 @@ pub struct BitMapAlloc {
 }
 
 impl BitMapAlloc {
+    /// Create an allocator for the physical memory after `addr`, the bitmap itself is stored in the
+    /// page at `addr`
     pub fn init(addr: usize) -> Mutex<Self> {
-        let bitmap = unsafe { bitmap::BitMap::zeroed(addr) };
+        let bitmap = unsafe { bitmap::BitMap::zeroed(phys_to_virt(addr)) };
         let base = addr;
 
         Mutex::new(Self { bitmap, base })
@@ -93,17 +142,22 @@ pub enum AllocatorError {
     AddrNotAligned { addr: usize, align: usize },
     #[error("Cannot allocate {size} pages")]
     InvalidSize { size: usize },
+    #[error("Cannot allocate a block of order {order}")]
+    InvalidOrder { order: usize },
+    #[error("Out of memory")]
+    OutOfMemory,
+    #[error("Address {addr:#x} was not allocated by this allocator")]
+    InvalidFree { addr: usize },
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::symbols;
-
     use super::*;
 
     #[test_case]
     fn test_balloc() {
-        let top = unsafe { symbols::HEAP0_TOP };
+        // the bitmap alloc only ever writes to its bitmap, so a single page is enough
+        let top = FRAME_ALLOC.lock().alloc(0).unwrap();
         let balloc = BitMapAlloc::init(top);
 
         let alloc0 = balloc.lock().alloc(4);
@@ -123,5 +177,7 @@ mod tests {
 
         let location = balloc.lock().alloc(6);
         assert_eq!(location, top + 0xc000);
+
+        FRAME_ALLOC.lock().free(top).unwrap();
     }
 }
@@ -1,20 +1,31 @@
-//! A tiered bitmap allocator
+//! A buddy allocator for physical page frames
 //!
-//! or maybe a buddy
+//! Memory is handed out in blocks of `PAGE_SIZE << order` bytes. A block of order `n` is always
+//! aligned to its own size, and is split into two "buddies" of order `n - 1` when a smaller block
+//! is needed. When both buddies are free again, they are merged back together.
 //!
-//! This is used for more granular allocations.
+//! Free blocks are kept in one intrusive, doubly linked list per order (the list nodes live inside
+//! the free memory itself). On top of that, we keep one byte of state per page, which tells us if
+//! the page is the head of a free or allocated block, and of what order. This lets us find out if
+//! a buddy is free in O(1), and lets `free` work without being told the size of the allocation.
 //!
-//! TODO: this is still a work in progress
+//! All addresses going in and out of the allocator are physical, the free blocks themselves are
+//! accessed through the direct map.
 
-use crate::PAGE_SIZE;
+use core::ptr::null_mut;
 
 use super::AllocatorError;
+use crate::PAGE_SIZE;
+use crate::vmem::{phys_to_virt, virt_to_phys};
 
-pub struct BuddyTree {
-    left: *mut BuddyTree,
-    right: *mut BuddyTree,
-    alloc_addr: usize,
-}
+/// Number of block sizes we support. The largest block is `PAGE_SIZE << (MAX_ORDER - 1)` (1 GiB)
+pub const MAX_ORDER: usize = 19;
+
+// the state byte of a page that is not the head of a block, or that we do not own
+const STATE_NONE: u8 = 0xFF;
+const STATE_FREE: u8 = 1 << 7;
+const STATE_USED: u8 = 1 << 6;
+const STATE_ORDER: u8 = 0x3F;
 
 // # Buddy system
 // Allocating 32M and 64M for example
@@ -24,43 +35,384 @@ pub struct BuddyTree {
 // |   64  |   64  |   64  |#######|
 // |###|32 |32 |32 |32 |32 |#######|
 
+/// Written at the start of every free block, links it into the free list of its order
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+#[derive(Debug)]
 pub struct BuddySystem {
+    /// address of the first page we keep track of
     base: usize,
+    /// number of pages between `base` and the end of the managed span
+    pages: usize,
+    /// one byte of state per page
+    meta: *mut u8,
+    free_lists: [*mut FreeBlock; MAX_ORDER],
+    free_pages: usize,
+    total_pages: usize,
 }
 
+// safety: the raw pointers point to memory that is owned by the allocator, and the allocator is
+// only ever accessed from behind a lock
+unsafe impl Send for BuddySystem {}
+
 impl BuddySystem {
+    /// An allocator that does not manage any memory, every allocation will fail
+    pub const fn empty() -> Self {
+        Self {
+            base: 0,
+            pages: 0,
+            meta: null_mut(),
+            free_lists: [null_mut(); MAX_ORDER],
+            free_pages: 0,
+            total_pages: 0,
+        }
+    }
+
+    /// Number of bytes of metadata required to keep track of `size` bytes of memory
+    pub const fn metadata_size(size: usize) -> usize {
+        size / PAGE_SIZE
+    }
+
+    /// Create an allocator spanning `size` bytes from `base`. No memory is available for
+    /// allocation until it is handed over using [BuddySystem::add_region].
+    ///
     /// # Safety
-    /// This is safe as long as the `addr` is valid
-    pub unsafe fn init(base: usize) -> Result<Self, AllocatorError> {
-        if base.is_multiple_of(PAGE_SIZE) {
+    /// `meta` must point to [BuddySystem::metadata_size] bytes of memory that are exclusively
+    /// owned by the allocator, and must not overlap with any memory given to `add_region`
+    pub unsafe fn init(base: usize, size: usize, meta: *mut u8) -> Result<Self, AllocatorError> {
+        if !base.is_multiple_of(PAGE_SIZE) {
             return Err(AllocatorError::AddrNotAligned {
                 addr: base,
                 align: PAGE_SIZE,
             });
         }
 
-        Ok(BuddySystem { base })
+        let pages = size / PAGE_SIZE;
+        if pages == 0 {
+            return Err(AllocatorError::InvalidSize { size: pages });
+        }
+
+        // safety: the caller guarantees meta is valid for `pages` bytes
+        unsafe { core::ptr::write_bytes(meta, STATE_NONE, pages) };
+
+        Ok(Self {
+            base,
+            pages,
+            meta,
+            free_lists: [null_mut(); MAX_ORDER],
+            free_pages: 0,
+            total_pages: 0,
+        })
+    }
+
+    /// Hand over the pages between `start` and `end` to the allocator. Partial pages on either
+    /// side of the range are ignored.
+    ///
+    /// # Safety
+    /// The memory must be valid, inside of the span given to [BuddySystem::init] and must not be
+    /// used by anything else
+    pub unsafe fn add_region(&mut self, start: usize, end: usize) {
+        let mut addr = crate::round_up_by(start, PAGE_SIZE);
+        let end = crate::round_down_by(end, PAGE_SIZE);
+
+        assert!(addr >= self.base, "region starts before the allocator span");
+        assert!(end <= self.end(), "region ends after the allocator span");
+
+        while addr < end {
+            // we pick the largest block that is aligned to its size and fits in what is left
+            let mut order = MAX_ORDER - 1;
+            while !addr.is_multiple_of(block_size(order)) || addr + block_size(order) > end {
+                order -= 1;
+            }
+
+            self.total_pages += 1 << order;
+            self.release(addr, order);
+            addr += block_size(order);
+        }
+    }
+
+    /// Allocate a block of `PAGE_SIZE << order` bytes, aligned to its size
+    pub fn alloc(&mut self, order: usize) -> Result<usize, AllocatorError> {
+        if order >= MAX_ORDER {
+            return Err(AllocatorError::InvalidOrder { order });
+        }
+
+        let mut current = (order..MAX_ORDER)
+            .find(|&order| !self.free_lists[order].is_null())
+            .ok_or(AllocatorError::OutOfMemory)?;
+
+        let addr = self.pop(current);
+
+        // split the block until it is the size we need, handing the upper halves back
+        while current > order {
+            current -= 1;
+            let buddy = addr + block_size(current);
+            self.push(buddy, current);
+        }
+
+        self.set_state(addr, STATE_USED | order as u8);
+        self.free_pages -= 1 << order;
+
+        Ok(addr)
+    }
+
+    /// Allocate at least `count` contiguous pages. The count is rounded up to a power of two
+    pub fn alloc_pages(&mut self, count: usize) -> Result<usize, AllocatorError> {
+        if count == 0 {
+            return Err(AllocatorError::InvalidSize { size: count });
+        }
+
+        self.alloc(order_for_pages(count))
+    }
+
+    /// Free a block that was returned by [BuddySystem::alloc]
+    pub fn free(&mut self, addr: usize) -> Result<(), AllocatorError> {
+        if !self.contains(addr) || !addr.is_multiple_of(PAGE_SIZE) {
+            return Err(AllocatorError::InvalidFree { addr });
+        }
+
+        let state = self.state(addr);
+        if state == STATE_NONE || state & STATE_USED == 0 {
+            return Err(AllocatorError::InvalidFree { addr });
+        }
+
+        let order = (state & STATE_ORDER) as usize;
+        self.release(addr, order);
+
+        Ok(())
+    }
+
+    /// Number of pages that are available for allocation
+    pub fn free_pages(&self) -> usize {
+        self.free_pages
+    }
+
+    /// Number of pages that have been handed over to the allocator
+    pub fn total_pages(&self) -> usize {
+        self.total_pages
+    }
+
+    /// Number of free blocks of every order, mostly just for debugging
+    pub fn free_blocks(&self) -> [usize; MAX_ORDER] {
+        let mut counts = [0; MAX_ORDER];
+
+        for (order, count) in counts.iter_mut().enumerate() {
+            let mut block = self.free_lists[order];
+            while !block.is_null() {
+                *count += 1;
+                // safety: every block in the list is a valid, free block
+                block = unsafe { (*block).next };
+            }
+        }
+
+        counts
+    }
+
+    /// Put a block back into the free lists, merging it with its buddy for as long as we can
+    fn release(&mut self, mut addr: usize, mut order: usize) {
+        self.set_state(addr, STATE_NONE);
+        self.free_pages += 1 << order;
+
+        while order < MAX_ORDER - 1 {
+            let buddy = addr ^ block_size(order);
+
+            if !self.contains(buddy) || self.state(buddy) != STATE_FREE | order as u8 {
+                break;
+            }
+
+            self.remove(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+
+        self.push(addr, order);
+    }
+
+    fn push(&mut self, addr: usize, order: usize) {
+        let block = phys_to_virt(addr) as *mut FreeBlock;
+        let head = self.free_lists[order];
+
+        // safety: the block is free memory owned by us, and head is either null or a valid block
+        unsafe {
+            block.write(FreeBlock {
+                next: head,
+                prev: null_mut(),
+            });
+
+            if let Some(head) = head.as_mut() {
+                head.prev = block;
+            }
+        }
+
+        self.free_lists[order] = block;
+        self.set_state(addr, STATE_FREE | order as u8);
     }
 
-    pub fn alloc(&mut self, bytes: usize) -> Result<usize, AllocatorError> {
-        if !bytes.is_power_of_two() {
-            return Err(AllocatorError::InvalidSize { size: bytes });
+    fn pop(&mut self, order: usize) -> usize {
+        let addr = virt_to_phys(self.free_lists[order] as usize);
+        self.remove(addr, order);
+        addr
+    }
+
+    fn remove(&mut self, addr: usize, order: usize) {
+        let block = phys_to_virt(addr) as *mut FreeBlock;
+
+        // safety: the state byte told us this is a free block, so it is part of the list
+        unsafe {
+            let FreeBlock { next, prev } = block.read();
+
+            match prev.as_mut() {
+                Some(prev) => prev.next = next,
+                None => self.free_lists[order] = next,
+            }
+
+            if let Some(next) = next.as_mut() {
+                next.prev = prev;
+            }
         }
 
-        Ok(0)
+        self.set_state(addr, STATE_NONE);
+    }
+
+    fn end(&self) -> usize {
+        self.base + self.pages * PAGE_SIZE
     }
+
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.base && addr < self.end()
+    }
+
+    fn state(&self, addr: usize) -> u8 {
+        let idx = (addr - self.base) / PAGE_SIZE;
+        // safety: the caller made sure the address is inside of the span, so idx is in bounds
+        unsafe { *self.meta.add(idx) }
+    }
+
+    fn set_state(&mut self, addr: usize, state: u8) {
+        let idx = (addr - self.base) / PAGE_SIZE;
+        // safety: the caller made sure the address is inside of the span, so idx is in bounds
+        unsafe { *self.meta.add(idx) = state };
+    }
+}
+
+#[inline]
+const fn block_size(order: usize) -> usize {
+    PAGE_SIZE << order
+}
+
+/// The smallest order that can hold `count` pages
+#[inline]
+pub const fn order_for_pages(count: usize) -> usize {
+    count.next_power_of_two().trailing_zeros() as usize
 }
 
 #[cfg(test)]
 mod test {
+    use alloc::alloc::{Layout, alloc, dealloc};
+    use alloc::vec::Vec;
+
     use super::*;
-    use crate::symbols;
+
+    const PAGES: usize = 16;
+    const SIZE: usize = PAGES * PAGE_SIZE;
+
+    /// Runs `test` with an allocator managing 16 pages of memory taken from the heap
+    fn with_buddy(test: impl FnOnce(&mut BuddySystem, usize)) {
+        let layout = Layout::from_size_align(SIZE, SIZE).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        let base = virt_to_phys(ptr as usize);
+        let mut meta = [0u8; BuddySystem::metadata_size(SIZE)];
+
+        let mut buddy = unsafe { BuddySystem::init(base, SIZE, meta.as_mut_ptr()) }.unwrap();
+        unsafe { buddy.add_region(base, base + SIZE) };
+
+        test(&mut buddy, base);
+
+        unsafe { dealloc(ptr, layout) };
+    }
 
     #[test_case]
     fn basic_asserts() {
-        let buddy = unsafe { BuddySystem::init(symbols::HEAP1_TOP) };
-        let mut buddy = buddy.unwrap();
-        assert!(buddy.alloc(3).is_err());
-        assert!(buddy.alloc(2).is_ok());
+        let mut meta = [0u8; 1];
+        let buddy = unsafe { BuddySystem::init(PAGE_SIZE + 1, PAGE_SIZE, meta.as_mut_ptr()) };
+        assert!(buddy.is_err());
+
+        with_buddy(|buddy, base| {
+            assert_eq!(buddy.total_pages(), PAGES);
+            assert_eq!(buddy.free_pages(), PAGES);
+
+            assert!(buddy.alloc(MAX_ORDER).is_err());
+            assert!(buddy.alloc_pages(0).is_err());
+
+            // the whole region should be a single block of order 4
+            assert_eq!(buddy.alloc(4).unwrap(), base);
+            assert_eq!(buddy.free_pages(), 0);
+        });
+    }
+
+    #[test_case]
+    fn fragmentation() {
+        with_buddy(|buddy, base| {
+            let pages: Vec<usize> = (0..PAGES).map(|_| buddy.alloc(0).unwrap()).collect();
+
+            // every page should be handed out exactly once, and inside of the region
+            for (i, page) in pages.iter().enumerate() {
+                assert!((base..base + SIZE).contains(page));
+                assert!(!pages[i + 1..].contains(page), "{page:#x} handed out twice");
+            }
+
+            // free every other page, we now have 8 free pages but no two of them are buddies
+            for page in pages
+                .iter()
+                .filter(|&&page| (page / PAGE_SIZE).is_multiple_of(2))
+            {
+                buddy.free(*page).unwrap();
+            }
+
+            assert_eq!(buddy.free_pages(), PAGES / 2);
+            assert!(buddy.alloc(1).is_err());
+            assert!(buddy.alloc(0).is_ok());
+        });
+    }
+
+    #[test_case]
+    fn coalescing() {
+        with_buddy(|buddy, base| {
+            let a = buddy.alloc(1).unwrap();
+            let b = buddy.alloc(0).unwrap();
+            let c = buddy.alloc(2).unwrap();
+            let d = buddy.alloc(0).unwrap();
+
+            buddy.free(b).unwrap();
+            buddy.free(d).unwrap();
+            buddy.free(a).unwrap();
+            buddy.free(c).unwrap();
+
+            // all of the blocks should have been merged back into a single one
+            let blocks = buddy.free_blocks();
+            assert_eq!(blocks[4], 1);
+            assert_eq!(blocks.iter().sum::<usize>(), 1);
+            assert_eq!(buddy.alloc(4).unwrap(), base);
+        });
+    }
+
+    #[test_case]
+    fn exhaustion() {
+        with_buddy(|buddy, _| {
+            let block = buddy.alloc(3).unwrap();
+            assert!(buddy.alloc(3).is_ok());
+            assert!(matches!(buddy.alloc(0), Err(AllocatorError::OutOfMemory)));
+
+            // double frees and frees of random addresses are caught
+            buddy.free(block).unwrap();
+            assert!(buddy.free(block).is_err());
+            assert!(buddy.free(block + PAGE_SIZE).is_err());
+            assert!(buddy.free(0x1000).is_err());
+
+            assert_eq!(buddy.alloc_pages(5).unwrap(), block);
+        });
     }
 }
@@ -0,0 +1,177 @@
+//! A lazily mapped region of the kernel's address space, used for large heap allocations
+//!
+//! Allocating only reserves a range of virtual addresses. The pages behind it are taken from the
+//! frame allocator (and zeroed) the first time they are touched, by way of a page fault. This means
+//! a large `Vec` that is never filled up does not use up any physical memory past its length.
+
+use alloc::collections::BTreeMap;
+
+use super::FRAME_ALLOC;
+use crate::PAGE_SIZE;
+use crate::riscv;
+use crate::sync::IrqMutex;
+use crate::vmem::{self, FaultError, KHEAP_BASE, KHEAP_SIZE, MapError, Perms};
+
+/// Taken by the page fault handler, so it keeps interrupts disabled while held
+static VHEAP: IrqMutex<VirtHeap> = IrqMutex::new(VirtHeap::new());
+
+/// Free ranges of the region, maps the start of a range to its (exclusive) end
+struct VirtHeap {
+    free: BTreeMap<usize, usize>,
+}
+
+impl VirtHeap {
+    const fn new() -> Self {
+        Self {
+            free: BTreeMap::new(),
+        }
+    }
+
+    /// First fit, the range is cut from the start of the first free range that is large enough
+    fn alloc(&mut self, size: usize) -> Option<usize> {
+        let (&start, &end) = self.free.iter().find(|&(start, end)| end - start >= size)?;
+
+        self.free.remove(&start);
+        if start + size < end {
+            self.free.insert(start + size, end);
+        }
+
+        Some(start)
+    }
+
+    /// Hand a range back, merging it with the free ranges on either side
+    fn free(&mut self, mut start: usize, mut end: usize) {
+        if let Some(next_end) = self.free.remove(&end) {
+            end = next_end;
+        }
+
+        if let Some((&prev_start, &prev_end)) = self.free.range(..start).next_back()
+            && prev_end == start
+        {
+            self.free.remove(&prev_start);
+            start = prev_start;
+        }
+
+        self.free.insert(start, end);
+    }
+
+    fn is_allocated(&self, addr: usize) -> bool {
+        let in_free_range = match self.free.range(..=addr).next_back() {
+            Some((_, &end)) => addr < end,
+            None => false,
+        };
+
+        !in_free_range
+    }
+}
+
+/// Make the region available. Until this is called, large allocations come straight from the frame
+/// allocator, as the kernel's page table is not active yet
+pub fn init() {
+    VHEAP.lock().free(KHEAP_BASE, KHEAP_BASE + KHEAP_SIZE);
+}
+
+/// Reserve `pages` pages of address space, returns `None` if the region is not initialised or full
+pub fn alloc(pages: usize) -> Option<usize> {
+    VHEAP.lock().alloc(pages * PAGE_SIZE)
+}
+
+/// Hand back a range of address space, along with any pages that were faulted in
+pub fn free(addr: usize, pages: usize) {
+    let mut vheap = VHEAP.lock();
+    let mut mapper = vmem::kernel_mapper();
+
+    for page in (addr..addr + pages * PAGE_SIZE).step_by(PAGE_SIZE) {
+        let Some((paddr, _)) = mapper.translate(page) else {
+            continue;
+        };
+
+        mapper.unmap(page, 1).expect("could not unmap heap page");
+        FRAME_ALLOC
+            .lock()
+            .free(paddr)
+            .expect("could not free heap page");
+    }
+
+    vheap.free(addr, addr + pages * PAGE_SIZE);
+}
+
+pub fn contains(addr: usize) -> bool {
+    (KHEAP_BASE..KHEAP_BASE + KHEAP_SIZE).contains(&addr)
+}
+
+/// Map a zeroed page at `vaddr`, if it is part of an allocation
+pub fn handle_fault(vaddr: usize) -> Result<(), FaultError> {
+    // holding the lock the whole time makes sure no other hart maps the same page under our feet
+    let vheap = VHEAP.lock();
+
+    if !vheap.is_allocated(vaddr) {
+        return Err(FaultError::Unmapped { vaddr });
+    }
+
+    let page = crate::round_down_by(vaddr, PAGE_SIZE);
+    let mut mapper = vmem::kernel_mapper();
+
+    // another hart got here first, and its mapping has not made it into our TLB yet. The TLB
+    // could still hold the fault, so we flush it either way
+    if mapper.translate(page).is_some() {
+        riscv::sfence_vma_addr(page);
+        return Ok(());
+    }
+
+    let paddr = FRAME_ALLOC
+        .lock()
+        .alloc(0)
+        .map_err(|_| MapError::OutOfMemory)?;
+    // safety: the page was just handed to us, and is reachable through the direct map
+    unsafe { core::ptr::write_bytes(vmem::phys_to_virt(paddr) as *mut u8, 0, PAGE_SIZE) };
+
+    mapper.map(paddr, page, Perms::READ_WRITE, 1)?;
+    riscv::sfence_vma_addr(page);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ranges() {
+        let mut vheap = VirtHeap::new();
+        vheap.free(0x10000, 0x20000);
+
+        let a = vheap.alloc(0x4000).unwrap();
+        let b = vheap.alloc(0x4000).unwrap();
+        assert_eq!((a, b), (0x10000, 0x14000));
+        assert!(vheap.is_allocated(a) && vheap.is_allocated(b + 0x3fff));
+        assert!(!vheap.is_allocated(0x18000));
+        assert!(vheap.alloc(0x10000).is_none());
+
+        // freeing both merges everything back into a single range
+        vheap.free(a, a + 0x4000);
+        vheap.free(b, b + 0x4000);
+        assert_eq!(vheap.free.len(), 1);
+        assert_eq!(vheap.alloc(0x10000), Some(0x10000));
+    }
+
+    #[test_case]
+    fn pages_are_mapped_on_first_touch() {
+        let addr = alloc(2).unwrap();
+        let mapper = vmem::kernel_mapper();
+        assert!(mapper.translate(addr).is_none());
+
+        let second = (addr + PAGE_SIZE) as *mut u64;
+        unsafe {
+            assert_eq!(second.read_volatile(), 0);
+            second.write_volatile(0xdead_beef);
+            assert_eq!(second.read_volatile(), 0xdead_beef);
+        }
+
+        // only the page we touched is backed by memory
+        assert!(mapper.translate(addr).is_none());
+        assert!(mapper.translate(addr + PAGE_SIZE).is_some());
+
+        free(addr, 2);
+        assert!(mapper.translate(addr + PAGE_SIZE).is_none());
+    }
+}
@@ -0,0 +1,205 @@
+//! Stack unwinding and symbolization
+//!
+//! Every function keeps a frame pointer, so a call stack is a linked list: `fp` points right above
+//! the frame of a function, with the return address at `fp - 8` and the frame pointer of its
+//! caller at `fp - 16`. Addresses are resolved against a symbol table that build.rs takes from the
+//! previously linked kernel (see `just build`). Without one we only print addresses, which
+//! addr2line can still make sense of.
+//!
+//! Nothing in here allocates, as it runs from the panic handler. Printing goes through `println!`,
+//! which only tries the console lock once the kernel is going down, see [crate::writer::set_fatal].
+
+use crate::riscv;
+use crate::stack;
+use crate::symbols;
+
+/// Give up on stacks deeper than this, a corrupted stack could make us go around in circles
+const MAX_DEPTH: usize = 64;
+
+const SYMBOL_SIZE: usize = 16;
+/// Space set aside for the symbol table. It is the same with or without symbols, so embedding them
+/// does not move any code
+const KSYMS_SIZE: usize = 512 * 1024;
+
+#[used]
+#[unsafe(link_section = ".ksyms")]
+static KSYMS: [u8; KSYMS_SIZE] = pad(include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin")));
+
+const fn pad(table: &[u8]) -> [u8; KSYMS_SIZE] {
+    assert!(
+        table.len() <= KSYMS_SIZE,
+        "the symbol table does not fit, raise KSYMS_SIZE"
+    );
+
+    let mut padded = [0; KSYMS_SIZE];
+    padded.split_at_mut(table.len()).0.copy_from_slice(table);
+    padded
+}
+
+/// Print the call stack of the caller
+#[inline(never)]
+pub fn print() {
+    crate::println!("backtrace of hart#{}:", riscv::hartid());
+    print_frames(Frames::new(riscv::fp()), 0);
+}
+
+/// Print the call stack of the code interrupted by a trap, starting at the instruction at `pc`
+pub fn print_trap(pc: usize, fp: usize) {
+    crate::println!("backtrace of hart#{} at the trap:", riscv::hartid());
+    print_frame(0, pc, lookup(pc));
+    print_frames(Frames::new(fp), 1);
+}
+
+fn print_frames(frames: Frames, first: usize) {
+    // return addresses point after the call, which can be past the end of a function that
+    // does not return
+    for (i, ra) in frames.enumerate() {
+        print_frame(first + i, ra, lookup(ra - 1));
+    }
+}
+
+fn print_frame(index: usize, addr: usize, symbol: Option<(&str, usize)>) {
+    match symbol {
+        Some((name, start)) => {
+            crate::println!("  #{index} {addr:#018x} {name}+{:#x}", addr - start)
+        }
+        None => crate::println!("  #{index} {addr:#018x} <unknown>"),
+    }
+}
+
+/// Walks the frame pointers, yielding return addresses
+struct Frames {
+    fp: usize,
+    /// the stack we are unwinding, we stop as soon as a frame pointer leaves it
+    stack: (usize, usize),
+    depth: usize,
+}
+
+impl Frames {
+    fn new(fp: usize) -> Self {
+        Self {
+            fp,
+            stack: stack_bounds(fp).unwrap_or((0, 0)),
+            depth: 0,
+        }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let (low, high) = self.stack;
+        let fp = self.fp;
+
+        if self.depth >= MAX_DEPTH || !fp.is_multiple_of(8) || fp < low + 16 || fp > high {
+            return None;
+        }
+
+        // safety: both words are on the stack we are walking
+        let (ra, next) = unsafe {
+            let fp = fp as *const usize;
+            (*fp.sub(1), *fp.sub(2))
+        };
+
+        if ra == 0 {
+            return None;
+        }
+
+        // the stack grows down, so our callers are always further up
+        self.fp = if next > fp { next } else { 0 };
+        self.depth += 1;
+        Some(ra)
+    }
+}
+
+/// The bounds of the stack `addr` is on
+fn stack_bounds(addr: usize) -> Option<(usize, usize)> {
+    if let Some(hart) = stack::owner(addr) {
+        return Some(stack::slot(hart));
+    }
+
+    let (top, bottom) = unsafe { (symbols::STACK_TOP, symbols::STACK_BOTTOM) };
+    (top..=bottom).contains(&addr).then_some((top, bottom))
+}
+
+/// The function `addr` is in, and where that function starts
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    // the table is filled in after the kernel is linked, so we go by where the linker put it
+    // instead of letting the compiler make assumptions about what is in it
+    let table = unsafe {
+        let (start, end) = (symbols::KSYMS_START, symbols::KSYMS_END);
+        core::slice::from_raw_parts(start as *const u8, end - start)
+    };
+
+    let count = read(table, 0)? as usize;
+    let entry = |i: usize| 8 + i * SYMBOL_SIZE;
+    let names = entry(count);
+
+    // the last symbol starting at or before addr
+    let (mut lo, mut hi) = (0, count);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if read(table, entry(mid))? as usize <= addr {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    let index = lo.checked_sub(1)?;
+
+    let symbol = read(table, entry(index))? as usize;
+    let size = read(table, entry(index) + 8)? as u32 as usize;
+    if size != 0 && addr >= symbol + size {
+        return None;
+    }
+
+    let name = |i: usize| read(table, entry(i) + 8).map(|word| (word >> 32) as usize);
+    let start = names + name(index)?;
+    // the rest of the table is zeroes, the last name ends at the first one
+    let end = match index + 1 < count {
+        true => names + name(index + 1)?,
+        false => table
+            .get(start..)?
+            .iter()
+            .position(|&byte| byte == 0)
+            .map_or(table.len(), |len| start + len),
+    };
+
+    let name = core::str::from_utf8(table.get(start..end)?).ok()?;
+    Some((name, symbol))
+}
+
+fn read(table: &[u8], at: usize) -> Option<u64> {
+    let bytes = table.get(at..at + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn depth() -> usize {
+        Frames::new(riscv::fp()).count()
+    }
+
+    #[inline(never)]
+    fn nested() -> usize {
+        core::hint::black_box(depth())
+    }
+
+    #[test_case]
+    fn walks_the_stack() {
+        let here = depth();
+        assert!(here > 0);
+        // one more function in between, one more frame
+        assert_eq!(nested(), here + 1);
+    }
+
+    #[test_case]
+    fn garbage_frame_pointers() {
+        assert_eq!(Frames::new(0).count(), 0);
+        assert_eq!(Frames::new(0xdead_beef).count(), 0);
+    }
+}
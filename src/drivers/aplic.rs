@@ -0,0 +1,201 @@
+//! A driver for the Advanced Platform-Level Interrupt Controller of the RISC-V Advanced Interrupt
+//! Architecture
+//!
+//! The APLIC takes the place of the PLIC for wired interrupts. Firmware owns the M-level (root)
+//! domain and delegates the sources to the S-level domain we drive. We only support MSI delivery
+//! mode: every source we use gets an identity from the [Imsic], and the APLIC forwards the source
+//! as a message to the interrupt file of the hart it is routed to.
+
+use alloc::collections::BTreeMap;
+
+use spin::{Mutex, Once};
+
+use super::DriverError;
+use super::imsic::Imsic;
+use crate::irq::{self, IrqChip};
+use crate::riscv;
+use crate::vmem::{self, Mapper, Perms};
+use crate::{PAGE_SIZE, round_up_by};
+
+const COMPATIBLE: &[&str] = &["riscv,aplic"];
+
+const DOMAINCFG: usize = 0x0;
+const SOURCECFG_BASE: usize = 0x4;
+const SETIENUM: usize = 0x1edc;
+const CLRIENUM: usize = 0x1ee0;
+const SETIPNUM_LE: usize = 0x2000;
+const TARGET_BASE: usize = 0x3000;
+/// Size of the register block when the fdt does not tell
+const REGS_SIZE: usize = 0x4000;
+
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM_MSI: u32 = 1 << 2;
+
+/// Source mode of a source that is not delegated to our domain, or not in use
+const SOURCECFG_INACTIVE: u32 = 0;
+/// Every wired interrupt on the platforms we run on is level triggered, active high
+const SOURCECFG_LEVEL_HIGH: u32 = 6;
+
+const TARGET_HART_SHIFT: u32 = 18;
+
+static APLIC: Once<Aplic> = Once::new();
+
+pub struct Aplic {
+    /// virtual address of the registers
+    base: usize,
+    /// number of interrupt sources, not counting source 0
+    num_sources: u32,
+    /// the source behind every identity we got from the IMSIC
+    sources: Mutex<BTreeMap<u32, u32>>,
+}
+
+impl Aplic {
+    /// Set up the S-level domain, has to run after [Imsic::init]
+    pub fn init(fdt: fdt::Fdt, mapper: &mut Mapper) -> Result<(), DriverError> {
+        if APLIC.is_completed() {
+            return Err(DriverError::AlreadyInitialised);
+        }
+
+        // the root domain lists the domains it delegates to, ours is the one at the bottom
+        let node = fdt
+            .all_nodes()
+            .filter(|node| {
+                node.compatible()
+                    .is_some_and(|compatible| compatible.all().any(|c| COMPATIBLE.contains(&c)))
+            })
+            .find(|node| node.property("riscv,children").is_none())
+            .ok_or(DriverError::DeviceNotFound)?;
+
+        if node.property("msi-parent").is_none() {
+            return Err(DriverError::Unimplimented);
+        }
+
+        if !Imsic::is_initialised() {
+            return Err(DriverError::DriverUninitialised);
+        }
+
+        let reg = node
+            .reg()
+            .and_then(|mut reg| reg.next())
+            .ok_or(DriverError::InvalidDevice {
+                reason: "aplic has no reg property",
+            })?;
+
+        let paddr = reg.starting_address as usize;
+        let size = round_up_by(reg.size.unwrap_or(REGS_SIZE), PAGE_SIZE);
+        let base = vmem::phys_to_virt(paddr);
+        mapper.map(paddr, base, Perms::READ_WRITE, size / PAGE_SIZE)?;
+
+        let num_sources = node
+            .property("riscv,num-sources")
+            .and_then(|sources| sources.as_usize())
+            .ok_or(DriverError::InvalidDevice {
+                reason: "aplic has no riscv,num-sources property",
+            })? as u32;
+
+        let aplic = Aplic {
+            base,
+            num_sources,
+            sources: Mutex::new(BTreeMap::new()),
+        };
+
+        for source in 1..=num_sources {
+            aplic.write(CLRIENUM, source);
+        }
+
+        aplic.write(DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM_MSI);
+        if aplic.read(DOMAINCFG) & DOMAINCFG_DM_MSI == 0 {
+            return Err(DriverError::InvalidDevice {
+                reason: "aplic does not support msi delivery mode",
+            });
+        }
+
+        irq::set_wired_chip(APLIC.call_once(|| aplic));
+
+        log::info!("[APLIC] initialised with {num_sources} sources at {paddr:#x}");
+        Ok(())
+    }
+
+    /// Called by the IMSIC when the identity of one of our sources comes in
+    fn handle_msi(id: u32) {
+        let Some(aplic) = APLIC.get() else { return };
+
+        // interrupts are disabled in trap context, so nobody holds this lock on our hart
+        let Some(source) = aplic.sources.lock().get(&id).copied() else {
+            log::warn!("[APLIC] no source behind identity {id}");
+            return;
+        };
+
+        irq::dispatch(aplic, source);
+
+        // forwarding the message cleared the pending bit, if the device still holds the line high
+        // this sets it again and we get another message
+        aplic.write(SETIPNUM_LE, source);
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u32, value) };
+    }
+}
+
+impl IrqChip for Aplic {
+    fn name(&self) -> &'static str {
+        "aplic"
+    }
+
+    fn enable(&self, source: u32, hartid: usize) -> Result<(), DriverError> {
+        if source == 0 || source > self.num_sources {
+            return Err(DriverError::InvalidDevice {
+                reason: "interrupt is out of range for the aplic",
+            });
+        }
+
+        let hart_index = Imsic::hart_index(hartid).ok_or(DriverError::InvalidDevice {
+            reason: "hart has no imsic interrupt file",
+        })?;
+
+        // firmware has to delegate the source to us, otherwise the source mode stays read-only
+        self.write(sourcecfg(source), SOURCECFG_LEVEL_HIGH);
+        if self.read(sourcecfg(source)) == SOURCECFG_INACTIVE {
+            return Err(DriverError::InvalidDevice {
+                reason: "interrupt is not delegated to the supervisor domain",
+            });
+        }
+
+        // a source keeps its identity, no matter how often it is enabled or where it goes
+        let id = riscv::interrupt::free(|| {
+            let mut sources = self.sources.lock();
+            match sources.iter().find(|&(_, &routed)| routed == source) {
+                Some((&id, _)) => Ok(id),
+                None => {
+                    let id = Imsic::register(Self::handle_msi)?;
+                    sources.insert(id, source);
+                    Ok::<_, DriverError>(id)
+                }
+            }
+        })?;
+
+        self.write(
+            target(source),
+            ((hart_index as u32) << TARGET_HART_SHIFT) | id,
+        );
+        self.write(SETIENUM, source);
+        Ok(())
+    }
+
+    fn disable(&self, source: u32) {
+        self.write(CLRIENUM, source);
+    }
+}
+
+const fn sourcecfg(source: u32) -> usize {
+    SOURCECFG_BASE + 4 * (source as usize - 1)
+}
+
+const fn target(source: u32) -> usize {
+    TARGET_BASE + 4 * source as usize
+}
@@ -0,0 +1,262 @@
+//! A driver for the Incoming MSI Controller of the RISC-V Advanced Interrupt Architecture
+//!
+//! Every hart has an S-level interrupt file. A device (or the APLIC, on behalf of a wired device)
+//! signals an interrupt by writing its identity to the file of the hart it wants to interrupt.
+//! The file itself is only accessed through CSRs, and only by the hart it belongs to.
+//!
+//! Identities are allocated globally and enabled in the file of every hart, so the hart an
+//! interrupt ends up on is decided by whoever sends the message. An identity either belongs to
+//! another interrupt controller (the APLIC), which gets called when it comes in, or to an
+//! [MsiSource] like a PCI device, in which case it is an irq of its own.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+
+use spin::{Mutex, Once};
+
+use super::{DriverError, IrqHandler};
+use crate::irq::{self, Irq, IrqChip};
+use crate::riscv;
+
+const COMPATIBLE: &[&str] = &["riscv,imsics"];
+
+/// The cause hart-local interrupt controllers use for supervisor external interrupts
+const CAUSE_S_EXTERNAL: u32 = 9;
+
+// registers of the interrupt file, see [riscv::imsic]
+const EIDELIVERY: usize = 0x70;
+const EITHRESHOLD: usize = 0x72;
+const EIE0: usize = 0xc0;
+
+static IMSIC: Once<Imsic> = Once::new();
+
+/// A device that signals interrupts by writing to an interrupt file, like an entry of a PCI
+/// device's MSI-X table
+pub trait MsiSource: Send + Sync {
+    /// Write `id` to the interrupt file at the physical `address` from now on
+    fn compose(&self, address: usize, id: u32);
+
+    fn mask(&self);
+
+    fn unmask(&self);
+}
+
+enum Owner {
+    /// the identity is dispatched by another interrupt controller
+    Controller(IrqHandler),
+    /// the identity is an irq of its own
+    Source(Box<dyn MsiSource>),
+}
+
+pub struct Imsic {
+    /// physical address of the interrupt file of every hart, by hart id
+    files: BTreeMap<usize, usize>,
+    /// physical address of the first interrupt file, and the distance between two of them
+    base: usize,
+    stride: usize,
+    /// highest identity the interrupt files implement
+    num_ids: u32,
+    /// identities that have been handed out, and who they belong to
+    owners: Mutex<BTreeMap<u32, Owner>>,
+}
+
+impl Imsic {
+    pub fn init(fdt: fdt::Fdt) -> Result<(), DriverError> {
+        if IMSIC.is_completed() {
+            return Err(DriverError::AlreadyInitialised);
+        }
+
+        // there is an M-level IMSIC as well, we want the one that signals S-mode external
+        // interrupts
+        let (node, contexts) = fdt
+            .all_nodes()
+            .filter(|node| {
+                node.compatible()
+                    .is_some_and(|compatible| compatible.all().any(|c| COMPATIBLE.contains(&c)))
+            })
+            .map(|node| (node, super::hart_contexts(fdt, node)))
+            .find(|(_, contexts)| {
+                contexts
+                    .iter()
+                    .any(|entry| matches!(entry, Some((_, CAUSE_S_EXTERNAL))))
+            })
+            .ok_or(DriverError::DeviceNotFound)?;
+
+        let base = node
+            .reg()
+            .and_then(|mut reg| reg.next())
+            .ok_or(DriverError::InvalidDevice {
+                reason: "imsic has no reg property",
+            })?
+            .starting_address as usize;
+
+        // without guest files, the interrupt files are one page each, in the order the harts are
+        // listed in interrupts-extended
+        let guest_bits = node
+            .property("riscv,guest-index-bits")
+            .and_then(|bits| bits.as_usize())
+            .unwrap_or(0);
+        let stride = crate::PAGE_SIZE << guest_bits;
+
+        let files = contexts
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, entry)| entry.map(|(hartid, _)| (hartid, base + index * stride)))
+            .collect();
+
+        let num_ids = node
+            .property("riscv,num-ids")
+            .and_then(|ids| ids.as_usize())
+            .ok_or(DriverError::InvalidDevice {
+                reason: "imsic has no riscv,num-ids property",
+            })? as u32;
+
+        IMSIC.call_once(|| Imsic {
+            files,
+            base,
+            stride,
+            num_ids,
+            owners: Mutex::new(BTreeMap::new()),
+        });
+
+        Self::inithart();
+
+        log::info!("[IMSIC] initialised with {num_ids} identities per hart at {base:#x}");
+        Ok(())
+    }
+
+    /// Turn on the interrupt file of the current hart, every hart has to call this for itself
+    pub fn inithart() {
+        let Some(imsic) = IMSIC.get() else { return };
+
+        // identities are masked by whoever sends them, so every hart takes all of them
+        for id in 1..=imsic.num_ids {
+            set_enabled(id, true);
+        }
+
+        riscv::imsic::write(EITHRESHOLD, 0);
+        riscv::imsic::write(EIDELIVERY, 1);
+    }
+
+    pub fn is_initialised() -> bool {
+        IMSIC.is_completed()
+    }
+
+    /// Allocate an identity for another interrupt controller, `handler` is called whenever it
+    /// comes in
+    pub fn register(handler: IrqHandler) -> Result<u32, DriverError> {
+        Self::allocate(Owner::Controller(handler))
+    }
+
+    /// Allocate an identity for `source`. It stays masked until the irq is requested
+    pub fn register_msi(source: Box<dyn MsiSource>) -> Result<Irq, DriverError> {
+        let imsic = IMSIC.get().ok_or(DriverError::DriverUninitialised)?;
+
+        source.mask();
+        let id = Self::allocate(Owner::Source(source))?;
+        Ok(irq::map(imsic, id))
+    }
+
+    /// Hand an identity back
+    #[allow(unused)]
+    pub fn unregister(id: u32) {
+        let Some(imsic) = IMSIC.get() else { return };
+        riscv::interrupt::free(|| imsic.owners.lock().remove(&id));
+    }
+
+    fn allocate(owner: Owner) -> Result<u32, DriverError> {
+        let imsic = IMSIC.get().ok_or(DriverError::DriverUninitialised)?;
+
+        riscv::interrupt::free(|| {
+            let mut owners = imsic.owners.lock();
+
+            let id = (1..=imsic.num_ids)
+                .find(|id| !owners.contains_key(id))
+                .ok_or(DriverError::OtherError("out of imsic identities"))?;
+
+            owners.insert(id, owner);
+            Ok(id)
+        })
+    }
+
+    /// The index of a hart's interrupt file, this is what the APLIC uses to address a hart
+    pub fn hart_index(hartid: usize) -> Option<usize> {
+        let imsic = IMSIC.get()?;
+        let file = imsic.files.get(&hartid)?;
+
+        Some((file - imsic.base) / imsic.stride)
+    }
+
+    /// Claim and handle every identity pending on this hart. Returns false if there is no IMSIC
+    pub fn handle_interrupt() -> bool {
+        let Some(imsic) = IMSIC.get() else {
+            return false;
+        };
+
+        loop {
+            let id = riscv::imsic::claim();
+            if id == 0 {
+                break;
+            }
+
+            // interrupts are disabled in trap context, so nobody holds this lock on our hart. It is
+            // not held while handling, the irq subsystem takes its own lock before ours
+            let controller = match imsic.owners.lock().get(&id) {
+                Some(Owner::Controller(handler)) => Some(*handler),
+                Some(Owner::Source(_)) => None,
+                None => {
+                    log::warn!("[IMSIC] nobody owns identity {id}");
+                    continue;
+                }
+            };
+
+            match controller {
+                Some(handler) => handler(id),
+                None => irq::dispatch(imsic, id),
+            }
+        }
+
+        true
+    }
+}
+
+impl IrqChip for Imsic {
+    fn name(&self) -> &'static str {
+        "imsic"
+    }
+
+    fn enable(&self, id: u32, hartid: usize) -> Result<(), DriverError> {
+        let address = self.files.get(&hartid).ok_or(DriverError::InvalidDevice {
+            reason: "hart has no imsic interrupt file",
+        })?;
+
+        riscv::interrupt::free(|| match self.owners.lock().get(&id) {
+            Some(Owner::Source(source)) => {
+                source.compose(*address, id);
+                source.unmask();
+                Ok(())
+            }
+            _ => Err(DriverError::InvalidDevice {
+                reason: "identity does not belong to a message signalling device",
+            }),
+        })
+    }
+
+    fn disable(&self, id: u32) {
+        riscv::interrupt::free(|| {
+            if let Some(Owner::Source(source)) = self.owners.lock().get(&id) {
+                source.mask();
+            }
+        });
+    }
+}
+
+fn set_enabled(id: u32, enabled: bool) {
+    // on RV64, only the even numbered registers exist, each holding 64 identities
+    let reg = EIE0 + (id as usize / 64) * 2;
+    let bit = 1 << (id % 64);
+
+    let value = riscv::imsic::read(reg);
+    let value = if enabled { value | bit } else { value & !bit };
+    riscv::imsic::write(reg, value);
+}
@@ -1,7 +1,11 @@
 use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
 
 use crate::systems::pci::{Device, PciMemory};
 
+pub mod aplic;
+pub mod imsic;
+pub mod plic;
 pub mod uart;
 pub mod virtio;
 
@@ -29,6 +33,18 @@ pub enum DriverError {
     OtherError(&'static str),
 }
 
+/// Called in trap context by a parent interrupt controller, with the number of the interrupt that
+/// fired. Drivers use the [crate::irq] subsystem instead
+pub type IrqHandler = fn(irq: u32);
+
+/// The first interrupt in the `interrupts` property of a node. The fdt crate glues cells
+/// together when a controller uses more than one (the APLIC has a trigger type in the second), so
+/// we read the first cell ourselves
+pub fn first_interrupt(node: fdt::node::FdtNode) -> Option<u32> {
+    let value = node.property("interrupts")?.value;
+    Some(u32::from_be_bytes(value.get(..4)?.try_into().ok()?))
+}
+
 pub struct MemoryRange {
     pub addr: usize,
     pub size_bytes: usize,
@@ -45,6 +61,41 @@ pub fn get_mem_addr(fdt: fdt::Fdt, compatible: &[&str]) -> Option<MemoryRange> {
     Some(MemoryRange { addr, size_bytes })
 }
 
+/// Resolve the `interrupts-extended` property of an interrupt controller. Every entry is a
+/// (hart id, interrupt cause) pair, the index of an entry is its context number. Entries that do
+/// not point at a hart's interrupt controller are `None`
+pub fn hart_contexts(fdt: fdt::Fdt, node: fdt::node::FdtNode) -> Vec<Option<(usize, u32)>> {
+    // the phandle of every hart's local interrupt controller, along with the id of the hart
+    let harts: BTreeMap<usize, usize> = fdt
+        .find_node("/cpus")
+        .into_iter()
+        .flat_map(|cpus| cpus.children())
+        .filter_map(|cpu| {
+            let hartid = cpu.property("reg")?.as_usize()?;
+            let intc = cpu
+                .children()
+                .find(|child| child.name.starts_with("interrupt-controller"))?;
+
+            Some((intc.property("phandle")?.as_usize()?, hartid))
+        })
+        .collect();
+
+    let Some(property) = node.property("interrupts-extended") else {
+        return Vec::new();
+    };
+
+    property
+        .value
+        .chunks_exact(8)
+        .map(|entry| {
+            let phandle = u32::from_be_bytes(entry[..4].try_into().unwrap()) as usize;
+            let cause = u32::from_be_bytes(entry[4..].try_into().unwrap());
+
+            harts.get(&phandle).map(|&hartid| (hartid, cause))
+        })
+        .collect()
+}
+
 pub fn allocate_bar_addrs(
     bars: BTreeSet<u8>,
     device: &Device,
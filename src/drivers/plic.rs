@@ -0,0 +1,184 @@
+//! A driver for the RISC-V Platform-Level Interrupt Controller
+//!
+//! Wired interrupts from devices (the UART, PCI INTx, ...) are routed through the PLIC. Every hart
+//! has an S-mode context with its own enable bits and priority threshold. When an enabled source
+//! is pending, the PLIC raises a supervisor external interrupt on the hart, which claims the
+//! source, dispatches it to the [irq] subsystem and signals completion.
+
+use alloc::collections::BTreeMap;
+
+use spin::Once;
+
+use super::DriverError;
+use crate::irq::{self, IrqChip};
+use crate::vmem::{self, Mapper, Perms};
+use crate::{PAGE_SIZE, round_up_by};
+
+const COMPATIBLE: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
+
+const PRIORITY_BASE: usize = 0x0;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// The cause hart-local interrupt controllers use for supervisor external interrupts
+const CAUSE_S_EXTERNAL: u32 = 9;
+/// Source 0 does not exist, claiming it means there was nothing to claim
+const NO_IRQ: u32 = 0;
+
+static PLIC: Once<Plic> = Once::new();
+
+pub struct Plic {
+    /// virtual address of the registers
+    base: usize,
+    /// number of interrupt sources, not counting source 0
+    ndev: u32,
+    /// the S-mode context of every hart, by hart id
+    contexts: BTreeMap<usize, usize>,
+}
+
+impl Plic {
+    pub fn init(fdt: fdt::Fdt, mapper: &mut Mapper) -> Result<(), DriverError> {
+        if PLIC.is_completed() {
+            return Err(DriverError::AlreadyInitialised);
+        }
+
+        let node = fdt
+            .find_compatible(COMPATIBLE)
+            .ok_or(DriverError::DeviceNotFound)?;
+        let reg = node
+            .reg()
+            .and_then(|mut reg| reg.next())
+            .ok_or(DriverError::InvalidDevice {
+                reason: "plic has no reg property",
+            })?;
+
+        let paddr = reg.starting_address as usize;
+        let size = round_up_by(reg.size.unwrap_or(CONTEXT_BASE), PAGE_SIZE);
+        let base = vmem::phys_to_virt(paddr);
+        mapper.map(paddr, base, Perms::READ_WRITE, size / PAGE_SIZE)?;
+
+        let ndev = node
+            .property("riscv,ndev")
+            .and_then(|ndev| ndev.as_usize())
+            .ok_or(DriverError::InvalidDevice {
+                reason: "plic has no riscv,ndev property",
+            })? as u32;
+
+        let contexts = super::hart_contexts(fdt, node)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(context, entry)| match entry {
+                Some((hartid, CAUSE_S_EXTERNAL)) => Some((hartid, context)),
+                _ => None,
+            })
+            .collect();
+
+        let plic = Plic {
+            base,
+            ndev,
+            contexts,
+        };
+
+        // start out with every source masked, and let every hart take any priority
+        for (&hartid, &context) in &plic.contexts {
+            for irq in 1..=ndev {
+                plic.set_enabled(context, irq, false);
+            }
+
+            plic.write(context_reg(context, CONTEXT_THRESHOLD), 0);
+            log::debug!("[PLIC] hart#{hartid} uses context {context}");
+        }
+
+        irq::set_wired_chip(PLIC.call_once(|| plic));
+
+        log::info!("[PLIC] initialised with {ndev} sources at {paddr:#x}");
+        Ok(())
+    }
+
+    /// Claim and handle every interrupt pending for this hart. Called on a supervisor external
+    /// interrupt, returns false if there is no PLIC
+    pub fn handle_interrupt(hartid: usize) -> bool {
+        let Some(plic) = PLIC.get() else {
+            return false;
+        };
+        let Some(&context) = plic.contexts.get(&hartid) else {
+            return false;
+        };
+
+        let claim = context_reg(context, CONTEXT_CLAIM);
+
+        loop {
+            let irq = plic.read(claim);
+            if irq == NO_IRQ {
+                break;
+            }
+
+            irq::dispatch(plic, irq);
+            plic.write(claim, irq);
+        }
+
+        true
+    }
+
+    fn set_enabled(&self, context: usize, irq: u32, enabled: bool) {
+        let reg = ENABLE_BASE + context * ENABLE_STRIDE + 4 * (irq as usize / 32);
+        let bit = 1 << (irq % 32);
+
+        let value = self.read(reg);
+        let value = if enabled { value | bit } else { value & !bit };
+        self.write(reg, value);
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u32, value) };
+    }
+}
+
+impl IrqChip for Plic {
+    fn name(&self) -> &'static str {
+        "plic"
+    }
+
+    fn enable(&self, irq: u32, hartid: usize) -> Result<(), DriverError> {
+        if irq == NO_IRQ || irq > self.ndev {
+            return Err(DriverError::InvalidDevice {
+                reason: "interrupt is out of range for the plic",
+            });
+        }
+
+        let context = *self
+            .contexts
+            .get(&hartid)
+            .ok_or(DriverError::InvalidDevice {
+                reason: "hart has no plic context",
+            })?;
+
+        // the irq could have been routed somewhere else before
+        for &other in self.contexts.values() {
+            self.set_enabled(other, irq, other == context);
+        }
+
+        self.write(PRIORITY_BASE + 4 * irq as usize, 1);
+        Ok(())
+    }
+
+    fn disable(&self, irq: u32) {
+        for &context in self.contexts.values() {
+            self.set_enabled(context, irq, false);
+        }
+
+        self.write(PRIORITY_BASE + 4 * irq as usize, 0);
+    }
+}
+
+const fn context_reg(context: usize, offset: usize) -> usize {
+    CONTEXT_BASE + context * CONTEXT_STRIDE + offset
+}
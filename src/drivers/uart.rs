@@ -1,49 +1,386 @@
 //! A driver for the 16550A UART device
 //!
-//! TODO: will continue the impl once I have some other stuff done
+//! The firmware console is this same UART, so the driver takes over from it without resetting
+//! anything it does not have to: we wait for the transmitter to drain before touching the line
+//! settings. Received bytes are taken in by the interrupt handler and kept in a ring buffer until
+//! they are [read](CharDriver::read). Without an interrupt controller, reading polls the device.
 #![allow(unused)]
 
 use core::{
     ptr::null_mut,
-    sync::atomic::{AtomicPtr, Ordering},
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
 };
 
 use alloc::boxed::Box;
+use spin::Once;
 
 use super::DriverError;
-use crate::vmem::{Mapper, Perms};
+use crate::irq::{self, Irq, IrqFlags, IrqReturn};
+use crate::riscv;
+use crate::tty::CharDevice;
+use crate::vmem::{self, Mapper, Perms};
+use crate::writer::{self, Console};
 
 const COMPATIBLE: &[&str] = &["ns16550a"];
 
+/// The baud rate we program, if the fdt tells us the input clock
+const BAUD: u32 = 115_200;
+/// Size of the transmit FIFO, the number of bytes we can write once the transmitter is empty
+const FIFO_SIZE: usize = 16;
+/// Bytes we keep until they are read, anything coming in on a full buffer is dropped
+const RX_BUFFER_SIZE: usize = 256;
+
+// register offsets
+const RBR: usize = 0;
+const THR: usize = 0;
+/// divisor latch, low byte, in place of RBR/THR while LCR_DLAB is set
+const DLL: usize = 0;
+const IER: usize = 1;
+/// divisor latch, high byte, in place of IER while LCR_DLAB is set
+const DLM: usize = 1;
+const FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+/// interrupt as soon as a single byte came in, the console is typed into by hand
+const FCR_TRIGGER_1: u8 = 0b00 << 6;
+/// 8 data bits, no parity, 1 stop bit
+const LCR_8N1: u8 = 0b11;
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+/// both the FIFO and the shift register are empty, everything has gone out
+const LSR_TX_IDLE: u8 = 1 << 6;
+
+/// How long to wait for a byte to come back in loopback mode, in polls of LSR
+const LOOPBACK_POLLS: usize = 100_000;
+
 static DRIVER_PTR: AtomicPtr<CharDriver> = AtomicPtr::new(null_mut());
+/// Number of bytes taken in by the receive interrupt
+static RX_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Bytes lost, either by the device (overrun) or because nobody read the ring buffer in time
+static RX_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Filled in trap context, so only ever locked under [riscv::interrupt::free]
+static RX: spin::Mutex<RingBuffer> = spin::Mutex::new(RingBuffer::new());
 
 /// Character device driver for ns16550a compatible UART devices
 #[derive(Debug)]
 pub struct CharDriver {
     base_addr: usize,
+    /// the interrupt we receive on, unset if there is no interrupt controller to route it
+    irq: Once<Irq>,
+}
+
+/// A fixed size FIFO of received bytes, it cannot allocate as it is filled in trap context
+struct RingBuffer {
+    bytes: [u8; RX_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; RX_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// `false` if the buffer is full, and the byte was dropped
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == RX_BUFFER_SIZE {
+            return false;
+        }
+
+        self.bytes[(self.head + self.len) % RX_BUFFER_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % RX_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
 }
 
 impl CharDriver {
+    const fn new(base_addr: usize) -> Self {
+        Self {
+            base_addr,
+            irq: Once::new(),
+        }
+    }
+
     pub fn init(fdt: fdt::Fdt, mapper: &mut Mapper) -> Result<(), DriverError> {
+        let node = fdt
+            .find_compatible(COMPATIBLE)
+            .ok_or(DriverError::DeviceNotFound)?;
         let mem_range = super::get_mem_addr(fdt, COMPATIBLE).ok_or(DriverError::DeviceNotFound)?;
 
-        let base_addr = mem_range.addr;
+        let base_addr = vmem::phys_to_virt(mem_range.addr);
 
-        Self::init_direct(base_addr)?;
+        if Self::instance().is_some() {
+            return Err(DriverError::AlreadyInitialised);
+        }
 
         assert_eq!(mem_range.size_bytes, 256);
-        mapper.map(base_addr, base_addr, Perms::READ_WRITE, 1)?;
+        mapper.map(mem_range.addr, base_addr, Perms::READ_WRITE, 1)?;
+
+        // nobody else sees the driver until the device has proven to work
+        let unpublished = Self::new(base_addr);
+        let clock = node
+            .property("clock-frequency")
+            .and_then(|clock| clock.as_usize());
+        unpublished.configure(clock);
+
+        if !unpublished.self_test() {
+            log::warn!("[UART] failed its loopback test, staying on the SBI console");
+            return Ok(());
+        }
+
+        let driver = Self::init_direct(base_addr)?;
+
+        // the firmware console is this same UART, so output carries on where it left off
+        writer::set_console(Console::Uart);
+
+        let hwirq = super::first_interrupt(node).ok_or(DriverError::InvalidDevice {
+            reason: "uart has no interrupts property",
+        })?;
+
+        let Some(irq) = irq::wired(hwirq) else {
+            log::warn!("[UART] no interrupt controller, input is polled");
+            return Ok(());
+        };
+
+        match irq::request_irq(irq, "uart", Self::handle_irq, IrqFlags::empty(), 0) {
+            Ok(()) => {
+                driver.irq.call_once(|| irq);
+                driver.write_reg(IER, IER_RX_AVAILABLE);
+            }
+            Err(err) => log::warn!("[UART] could not route irq {irq}, input is polled: {err}"),
+        }
 
         Ok(())
     }
 
-    fn get_instance() -> Option<&'static mut Self> {
-        unsafe { DRIVER_PTR.load(Ordering::Relaxed).as_mut() }
+    /// Set up the line as 8N1 with FIFOs, at [BAUD] if we know the input `clock`. Without it we
+    /// keep the divisor the firmware left behind
+    fn configure(&self, clock: Option<usize>) {
+        // whatever the firmware is still sending would be garbled by a change of line settings
+        self.wait_idle();
+
+        // no interrupts until we have somewhere to put the bytes
+        self.write_reg(IER, 0);
+
+        // nothing can be printed while LCR_DLAB is set, THR is the divisor latch then
+        let divisor = clock.map(|clock| {
+            let divisor = (clock / (16 * BAUD as usize)).clamp(1, u16::MAX as usize) as u16;
+            let [low, high] = divisor.to_le_bytes();
+
+            self.write_reg(LCR, LCR_DLAB);
+            self.write_reg(DLL, low);
+            self.write_reg(DLM, high);
+            divisor
+        });
+
+        // this also clears LCR_DLAB
+        self.write_reg(LCR, LCR_8N1);
+        self.write_reg(
+            FCR,
+            FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | FCR_TRIGGER_1,
+        );
+        // OUT2 gates the interrupt line on PC style UARTs
+        self.write_reg(MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+
+        match (clock, divisor) {
+            (Some(clock), Some(divisor)) => {
+                log::debug!("[UART] {BAUD} baud from a {clock}Hz clock, divisor {divisor}")
+            }
+            _ => log::debug!("[UART] no clock-frequency, keeping the baud rate as it is"),
+        }
+    }
+
+    /// Send a byte through the device in loopback mode, and check it comes back in. Nothing can be
+    /// printed while this runs, as the console shares the UART
+    fn self_test(&self) -> bool {
+        const PATTERN: u8 = 0xa5;
+
+        riscv::interrupt::free(|| {
+            self.wait_idle();
+            let ier = self.read_reg(IER);
+            let mcr = self.read_reg(MCR);
+
+            // without the receive interrupt, the byte stays in the FIFO for us
+            self.write_reg(IER, 0);
+            self.write_reg(MCR, mcr | MCR_LOOPBACK);
+
+            // keep whatever was typed before, instead of mistaking it for our byte
+            self.drain_fifo();
+            self.write_reg(THR, PATTERN);
+
+            let received = (0..LOOPBACK_POLLS).find_map(|_| {
+                riscv::pause();
+                (self.read_reg(LSR) & LSR_DATA_READY != 0).then(|| self.read_reg(RBR))
+            });
+
+            self.write_reg(MCR, mcr);
+            self.write_reg(IER, ier);
+            received == Some(PATTERN)
+        })
+    }
+
+    /// Write `s` out, waiting for room in the transmitter. `false` if there is no driver yet
+    pub fn write_str(s: &str) -> bool {
+        let Some(driver) = Self::instance() else {
+            return false;
+        };
+
+        for byte in s.bytes() {
+            driver.write_byte(byte);
+        }
+
+        true
+    }
+
+    /// Write all of `bytes`, waiting for room in the transmitter
+    pub fn write(&self, bytes: &[u8]) {
+        bytes.iter().for_each(|&byte| self.write_byte(byte));
+    }
+
+    /// Write as much of `bytes` as fits in the transmitter right now, and return how much that was
+    pub fn try_write(&self, bytes: &[u8]) -> usize {
+        // the LSR only tells us when the FIFO is completely empty, not how full it is
+        if self.read_reg(LSR) & LSR_THR_EMPTY == 0 {
+            return 0;
+        }
+
+        let len = bytes.len().min(FIFO_SIZE);
+        bytes[..len]
+            .iter()
+            .for_each(|&byte| self.write_reg(THR, byte));
+        len
+    }
+
+    fn write_byte(&self, byte: u8) {
+        while self.read_reg(LSR) & LSR_THR_EMPTY == 0 {
+            riscv::pause();
+        }
+        self.write_reg(THR, byte);
     }
 
-    fn init_direct(base_addr: usize) -> Result<(), DriverError> {
-        let driver = Box::new(CharDriver { base_addr });
-        let driver_ptr = Box::leak(driver);
+    /// Move received bytes into `buf`, without waiting for any. Returns the number of bytes read
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        riscv::interrupt::free(|| {
+            // without an interrupt nothing empties the device for us
+            if !self.has_irq() {
+                self.drain_fifo();
+            }
+
+            let mut rx = RX.lock();
+            buf.iter_mut()
+                .map_while(|slot| rx.pop().map(|byte| *slot = byte))
+                .count()
+        })
+    }
+
+    /// The next received byte, if there is one
+    pub fn read_byte(&self) -> Option<u8> {
+        let mut byte = 0;
+        (self.read(core::slice::from_mut(&mut byte)) == 1).then_some(byte)
+    }
+
+    /// Whether we can take input without polling, the interrupt wakes up a hart waiting on it
+    pub fn has_irq(&self) -> bool {
+        self.irq.is_completed()
+    }
+
+    /// Move everything in the receive FIFO into the ring buffer. Interrupts have to be disabled
+    fn drain_fifo(&self) -> bool {
+        let mut rx = RX.lock();
+        let mut any = false;
+
+        loop {
+            let lsr = self.read_reg(LSR);
+            if lsr & LSR_OVERRUN != 0 {
+                RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+            if lsr & LSR_DATA_READY == 0 {
+                return any;
+            }
+
+            let byte = self.read_reg(RBR);
+            RX_BYTES.fetch_add(1, Ordering::Relaxed);
+            if !rx.push(byte) {
+                RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+            any = true;
+        }
+    }
+
+    /// Take a byte straight from the receive FIFO, without going through the ring buffer
+    fn receive_byte(&self) -> Option<u8> {
+        if self.read_reg(LSR) & LSR_DATA_READY == 0 {
+            return None;
+        }
+
+        RX_BYTES.fetch_add(1, Ordering::Relaxed);
+        Some(self.read_reg(RBR))
+    }
+
+    /// Drain the receive FIFO
+    fn handle_irq(_irq: Irq, _dev: usize) -> IrqReturn {
+        let Some(driver) = Self::instance() else {
+            return IrqReturn::None;
+        };
+
+        match driver.drain_fifo() {
+            true => IrqReturn::Handled,
+            false => IrqReturn::None,
+        }
+    }
+
+    /// Wait until everything written has gone out on the line
+    fn wait_idle(&self) {
+        while self.read_reg(LSR) & LSR_TX_IDLE == 0 {
+            riscv::pause();
+        }
+    }
+
+    fn read_reg(&self, offset: usize) -> u8 {
+        unsafe { core::ptr::read_volatile((self.base_addr + offset) as *const u8) }
+    }
+
+    fn write_reg(&self, offset: usize, value: u8) {
+        unsafe { core::ptr::write_volatile((self.base_addr + offset) as *mut u8, value) };
+    }
+
+    /// The driver, once it has been initialised
+    pub fn instance() -> Option<&'static Self> {
+        // safety: the driver is leaked once published, and never changed through the pointer
+        unsafe { DRIVER_PTR.load(Ordering::Acquire).as_ref() }
+    }
+
+    /// Publish a driver for the device at `base_addr`, which has to be set up already
+    fn init_direct(base_addr: usize) -> Result<&'static Self, DriverError> {
+        let driver_ptr = Box::leak(Box::new(CharDriver::new(base_addr)));
 
         // only load value if previous value is null_mut
         let res = DRIVER_PTR.compare_exchange(
@@ -59,10 +396,50 @@ impl CharDriver {
             return Err(DriverError::AlreadyInitialised);
         }
 
-        Ok(())
+        Ok(driver_ptr)
     }
 }
 
+impl CharDevice for CharDriver {
+    fn read(&self, buf: &mut [u8]) -> usize {
+        CharDriver::read(self, buf)
+    }
+
+    fn poll(&self, buf: &mut [u8]) -> usize {
+        // whoever we interrupted could be holding RX for good, so it is only tried. The rest comes
+        // straight from the device
+        let buffered = match RX.try_lock() {
+            Some(mut rx) => buf
+                .iter_mut()
+                .map_while(|slot| rx.pop().map(|byte| *slot = byte))
+                .count(),
+            None => 0,
+        };
+
+        buffered
+            + buf[buffered..]
+                .iter_mut()
+                .map_while(|slot| self.receive_byte().map(|byte| *slot = byte))
+                .count()
+    }
+
+    fn pending(&self) -> bool {
+        riscv::interrupt::free(|| RX.lock().len != 0)
+    }
+
+    fn interrupt_driven(&self) -> bool {
+        self.has_irq()
+    }
+}
+
+/// Bytes received and lost since boot
+pub fn rx_stats() -> (usize, usize) {
+    (
+        RX_BYTES.load(Ordering::Relaxed),
+        RX_DROPPED.load(Ordering::Relaxed),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -78,4 +455,63 @@ mod tests {
         let driver = CharDriver::init_direct(0x10000000);
         assert!(driver.is_err(), "is_err failed {driver:?}");
     }
+
+    #[test_case]
+    fn ring_buffer() {
+        let mut ring = RingBuffer::new();
+        assert_eq!(ring.pop(), None);
+
+        // go around the end a few times
+        for round in 0..3 {
+            for i in 0..RX_BUFFER_SIZE {
+                assert!(ring.push((i + round) as u8));
+            }
+            assert!(!ring.push(0), "pushed onto a full buffer");
+
+            for i in 0..RX_BUFFER_SIZE {
+                assert_eq!(ring.pop(), Some((i + round) as u8));
+            }
+            assert_eq!(ring.pop(), None);
+            ring.push(0xff);
+            ring.pop();
+        }
+    }
+
+    #[test_case]
+    fn loopback() {
+        let driver = CharDriver::instance().expect("uart is initialised before tests");
+        assert!(driver.self_test(), "loopback self test failed");
+    }
+
+    #[test_case]
+    fn receive_interrupt() {
+        let Some(driver) = CharDriver::instance() else {
+            return;
+        };
+
+        if !driver.has_irq() {
+            crate::println!("no interrupt controller, skipping");
+            return;
+        }
+
+        // throw away anything that was typed, so the byte we read is ours
+        while driver.read_byte().is_some() {}
+        let before = RX_BYTES.load(Ordering::Relaxed);
+
+        // in loopback mode, everything we send comes right back in. We cannot print anything until
+        // loopback is turned off again, as the console shares this UART
+        driver.wait_idle();
+        let mcr = driver.read_reg(MCR);
+        driver.write_reg(MCR, mcr | MCR_LOOPBACK);
+        driver.write_reg(THR, b'k');
+
+        let received = (0..1_000_000).any(|_| {
+            riscv::pause();
+            RX_BYTES.load(Ordering::Relaxed) > before
+        });
+
+        driver.write_reg(MCR, mcr);
+        assert!(received, "no receive interrupt was taken");
+        assert_eq!(driver.read_byte(), Some(b'k'));
+    }
 }
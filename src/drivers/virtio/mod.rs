@@ -12,11 +12,18 @@ use virtqueue::VirtQueue;
 
 use super::DriverError;
 use super::regcell::*;
-use crate::systems::pci::{Device, PciMemory};
+use crate::irq::{self, Irq, IrqFlags, IrqReturn};
+use crate::systems::pci::{CAP_ID_VENDOR, Device, MsiCapability, MsiVectors, PciMemory};
+use crate::vmem;
 
 // ID_PAIR for a virtio block device, I will add more support once this is done
 pub const ID_PAIR: (u16, u16) = (0x1af4, 0x1001);
 
+/// The vector configuration change interrupts are sent on, queues get the ones after it
+const CONFIG_VECTOR: u16 = 0;
+/// Written back by the device when it could not take the vector we gave it
+const NO_VECTOR: u16 = 0xffff;
+
 pub fn init(device: Device, mem: &mut PciMemory) {
     log::info!("[VIRTIO] initialising VirtIO PCI driver");
 
@@ -94,16 +101,20 @@ fn read_cap_data(cap: &[CapData]) -> Option<Data> {
 
 fn init_pci(device: &Device, mem: &mut PciMemory) -> Result<VirtioPciCommonCfg, DriverError> {
     let mut cap = Vec::<CapData>::new();
-    device.get_capabilities::<CapData, Vec<CapData>>(&mut cap);
+    device.get_capabilities::<CapData, Vec<CapData>>(CAP_ID_VENDOR, &mut cap);
 
     let cap_data = read_cap_data(&cap).ok_or(DriverError::OtherError(
         "Device capability list is incomplete",
     ))?;
 
+    let msi = MsiCapability::parse(device);
+
     let bars: BTreeSet<u8> = cap
         .iter()
+        .map(|cap| cap.bar)
+        .chain(msi.and_then(|msi| msi.table_bar()))
         // bar 0 is going to be for PIO, so we skip it on RISCV TODO-ARCH-RISCV
-        .filter_map(|cap| if cap.bar > 0 { Some(cap.bar) } else { None })
+        .filter(|&bar| bar > 0)
         .collect();
 
     let bar_addrs = super::allocate_bar_addrs(bars, device, mem)?;
@@ -112,7 +123,17 @@ fn init_pci(device: &Device, mem: &mut PciMemory) -> Result<VirtioPciCommonCfg,
     let address = bar_addrs.get(&data.bar).ok_or(DriverError::OtherError(
         "address for bar has not been allocated",
     ))?;
-    let config = unsafe { VirtioPciCommonCfg::from_raw(address + data.offset as usize) };
+    // the bars hold physical addresses, we reach them through the direct map
+    let address = vmem::phys_to_virt(*address);
+    let mut config = unsafe { VirtioPciCommonCfg::from_raw(address + data.offset as usize) };
+
+    config.vectors = match request_vectors(device, msi, &config, &bar_addrs) {
+        Ok(vectors) => Some(vectors),
+        Err(error) => {
+            log::warn!("[VIRTIO] device will not send interrupts: {error}");
+            None
+        }
+    };
 
     // device data stuff
     let blk_cfg_data = cap_data.device;
@@ -122,14 +143,75 @@ fn init_pci(device: &Device, mem: &mut PciMemory) -> Result<VirtioPciCommonCfg,
     Ok(config)
 }
 
+/// One vector for configuration changes, and one for every queue as long as the device has enough
+fn request_vectors(
+    device: &Device,
+    msi: Option<MsiCapability>,
+    config: &VirtioPciCommonCfg,
+    bar_addrs: &BTreeMap<u8, usize>,
+) -> Result<MsiVectors, DriverError> {
+    // virtio only knows about MSI-X vectors
+    let Some(msi @ MsiCapability::MsiX { .. }) = msi else {
+        return Err(DriverError::InvalidDevice {
+            reason: "device does not support msi-x",
+        });
+    };
+
+    let num_queues = unsafe { &*config.common_raw }.num_queues.get() as usize;
+    let count = msi.max_vectors().min(1 + num_queues);
+    let vectors = MsiVectors::request(device, count, bar_addrs)?;
+
+    for vector in 0..count {
+        let irq = vectors.irq(vector).expect("vector was just requested");
+
+        let res = match vector as u16 {
+            CONFIG_VECTOR => {
+                irq::request_irq(irq, "virtio-config", config_interrupt, IrqFlags::empty(), 0)
+            }
+            _ => irq::request_deferred_irq(
+                irq,
+                "virtio-queue",
+                queue_interrupt,
+                queue_work,
+                IrqFlags::empty(),
+                vector,
+            ),
+        };
+
+        res.map_err(|_| DriverError::OtherError("could not request the irq of a vector"))?;
+    }
+
+    Ok(vectors)
+}
+
+fn config_interrupt(_irq: Irq, _vector: usize) -> IrqReturn {
+    log::debug!("[VIRTIO] device configuration has changed");
+    IrqReturn::Handled
+}
+
+/// With MSI-X, there is no ISR status to read and acknowledge, the vector says it all
+fn queue_interrupt(_irq: Irq, _vector: usize) -> IrqReturn {
+    IrqReturn::Defer
+}
+
+/// [VirtQueue] does not keep track of buffers yet, so there is no used ring to hand this to
+fn queue_work(_irq: Irq, vector: usize) {
+    log::trace!("[VIRTIO] used buffers signalled on vector {vector}");
+}
+
 struct VirtioPciCommonCfg {
     common_raw: *mut VirtioPciCommonCfgRaw,
+    /// `None` if the device cannot send us interrupts
+    vectors: Option<MsiVectors>,
 }
 
 impl VirtioPciCommonCfg {
     pub unsafe fn from_raw(addr: usize) -> Self {
         let inner = addr as *mut VirtioPciCommonCfgRaw;
-        Self { common_raw: inner }
+        Self {
+            common_raw: inner,
+            vectors: None,
+        }
     }
 
     // Page 59 of VirtIO spec v1.3
@@ -183,6 +265,7 @@ impl VirtioPciCommonCfg {
 
         // STEP 7
         let virtqueues = self.probe_virtqueues();
+        self.assign_vectors(virtqueues.keys().copied())?;
         VirtQueue::init(virtqueues);
 
         // STEP 8
@@ -192,6 +275,40 @@ impl VirtioPciCommonCfg {
         Ok(())
     }
 
+    /// Tell the device which vector to use for what. Queues share vectors when there are not enough
+    fn assign_vectors(&self, queues: impl Iterator<Item = u16>) -> Result<(), DriverError> {
+        let Some(vectors) = &self.vectors else {
+            return Ok(());
+        };
+
+        let inner = unsafe { &*self.common_raw };
+
+        inner.config_msix_vector.set(CONFIG_VECTOR);
+        if inner.config_msix_vector.get() == NO_VECTOR {
+            return Err(DriverError::OtherError(
+                "device did not take the config vector",
+            ));
+        }
+
+        let queue_vectors = vectors.len() as u16 - 1;
+        for queue in queues {
+            let vector = match queue_vectors {
+                0 => CONFIG_VECTOR,
+                n => 1 + queue % n,
+            };
+
+            inner.queue_select.set(queue);
+            inner.queue_msix_vector.set(vector);
+            if inner.queue_msix_vector.get() == NO_VECTOR {
+                return Err(DriverError::OtherError(
+                    "device did not take a queue vector",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
     fn probe_virtqueues(&self) -> BTreeMap<u16, u16> {
         let inner = unsafe { &*self.common_raw };
 
@@ -1,31 +1,119 @@
-.section .text.boot
-.global _start
-_start:
-    # load the first 32 bits from the addr stored in a1
-    lwu t0, 0(a1)
-    # the magic value for fdt parsing, be encoded
-    li t1, 0xedfe0dd0
+.equ SATP_SV39, (8 << 60)
+# difference between the address the kernel is linked at, and where it is loaded
+.equ KERNEL_OFFSET, 0xffffffff00000000
+# V | R | W | X | A | D
+.equ PTE_KERNEL, 0xcf
+# from stack.rs (see main.rs), the emergency stack is the lowest page of a hart's stack slot
+.equ KSTACK_SLOT, {kstack_slot}
+# offsets of the fields of percpu::Cpu, see main.rs
+.equ CPU_HARTID, {cpu_hartid}
+.equ CPU_STACK_TOP, {cpu_stack_top}
 
-    bne t0, t1, alloced_stack
-    j static_stack
+# turn on paging, with the root page table at the physical address in t0
+.macro write_satp
+    srli t0, t0, 12
+    li t1, SATP_SV39
+    or t0, t0, t1
+    sfence.vma
+    csrw satp, t0
+    sfence.vma
+.endm
 
-static_stack:
-    la sp, __stack_bottom
-    j setstvec
-alloced_stack:
-    mv sp, a1
-    j setstvec
+# We are loaded (and started) at a physical address, but linked in the higher half. Before we can
+# touch any symbol, we turn on the boot page table and jump to where we are supposed to be. The
+# real page table is set up later on by vmem::init
+.macro enable_boot_paging
+    lla t0, boot_pagetable
+    write_satp
 
-setstvec:
+    lla t0, 1f
+    li t1, KERNEL_OFFSET
+    add t0, t0, t1
+    jr t0
+1:
+.endm
+
+.macro setup_trap
     la t2, ktrapvec
     csrw stvec, t2
-setsie:
     li t2, 0x222
     csrw sie, t2
-setsstatus:
     li t2, (1 << 1)
     csrrs zero, sstatus, t2
+.endm
 
-callstart:
-    bne t0, t1, kinit
+.section .text.boot
+# the boot hart, a0 holds the hart id and a1 the (physical) address of the fdt
+.global _start
+_start:
+    enable_boot_paging
+    # the boot hart gets its per-CPU block in .bss, as a copy of the template
+    la t0, __percpu_start
+    la t1, __percpu_end
+    la tp, __percpu_boot
+    mv t2, tp
+2:
+    bgeu t0, t1, 3f
+    ld t3, 0(t0)
+    sd t3, 0(t2)
+    addi t0, t0, 8
+    addi t2, t2, 8
+    j 2b
+3:
+    sd a0, CPU_HARTID(tp)
+    la sp, __stack_bottom
+    la t0, boot_emergency_stack_top
+    csrw sscratch, t0
+    setup_trap
     call start
+
+# every other hart, a0 holds the hart id and a1 the per-CPU block allocated for it
+.global _start_hart
+_start_hart:
+    enable_boot_paging
+    # the boot page table does not have the kernel stacks, so we move to the real one right away
+    la t0, PAGE_TABLE
+    ld t0, 0(t0)
+    write_satp
+    mv a0, a1
+    ld a1, CPU_STACK_TOP(a0)
+
+# a0 holds the per-CPU block of the hart and a1 the top of its stack in the kernel stack region,
+# paging is enabled
+.global enter_hart
+enter_hart:
+    mv tp, a0
+    mv sp, a1
+    li t0, KSTACK_SLOT - 4096
+    sub t0, a1, t0
+    csrw sscratch, t0
+    setup_trap
+    call kinit
+
+.section .bss
+.balign 16
+# used by the boot hart until it moves to a stack with guard pages
+boot_emergency_stack:
+    .skip 4096
+boot_emergency_stack_top:
+
+.section .data
+.balign 4096
+boot_pagetable:
+    # the gigapage we are loaded into, so we do not fault right after turning on paging
+    .dword 0
+    .dword 0
+    .dword (0x80000 << 10) | PTE_KERNEL
+    .zero 8 * (256 - 3)
+
+    # the first 64 GiB of physical memory, mapped at the start of the direct map
+    .set gigapage, 0
+    .rept 64
+    .dword ((gigapage << 18) << 10) | PTE_KERNEL
+    .set gigapage, gigapage + 1
+    .endr
+    .zero 8 * (510 - 256 - 64)
+
+    # the kernel, linked at 0xffffffff80000000 and above
+    .dword (0x80000 << 10) | PTE_KERNEL
+    .dword 0
@@ -0,0 +1,488 @@
+//! Interrupt requests, independent of the controller they come in through
+//!
+//! Interrupt controllers ([IrqChip]s) map their hardware interrupt numbers to global [Irq]
+//! numbers with [map], and call [dispatch] when one fires. Drivers ask for an [Irq] with
+//! [request_irq], and get their fast handler called in trap context. Work that should not run with
+//! interrupts disabled goes in a deferred handler, which runs later on from [run_deferred].
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::{Once, RwLock};
+
+use crate::MAX_HARTS;
+use crate::drivers::DriverError;
+use crate::riscv;
+
+/// Global interrupt number, handed out by [map]
+pub type Irq = u32;
+
+/// Called in trap context with the irq that fired and the `dev` given to [request_irq]
+pub type Handler = fn(irq: Irq, dev: usize) -> IrqReturn;
+/// Called with interrupts enabled, after the fast handler returned [IrqReturn::Defer]
+pub type Deferred = fn(irq: Irq, dev: usize);
+
+static IRQS: RwLock<Irqs> = RwLock::new(Irqs::new());
+/// Set when there is deferred work on any irq
+static DEFERRED: AtomicBool = AtomicBool::new(false);
+/// The controller wired interrupts of devices in the fdt go through
+static WIRED: Once<&'static dyn IrqChip> = Once::new();
+
+/// An interrupt controller that routes the interrupts it takes to harts
+pub trait IrqChip: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Route `hwirq` to `hartid`, and let it through
+    fn enable(&self, hwirq: u32, hartid: usize) -> Result<(), DriverError>;
+
+    /// Stop `hwirq` from reaching any hart
+    fn disable(&self, hwirq: u32);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The interrupt did not come from our device, only makes sense on a shared line
+    None,
+    Handled,
+    /// Handled, and the deferred handler has work to do
+    Defer,
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct IrqFlags: u8 {
+        /// Other drivers can request the irq too, every handler runs when it fires
+        const SHARED = 1 << 0;
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IrqError {
+    #[error("irq {0} does not exist")]
+    NoSuchIrq(Irq),
+    #[error("irq {0} is in use, and not shared")]
+    Busy(Irq),
+    #[error("irq {0} was not requested by this device")]
+    NotRequested(Irq),
+    #[error("interrupt controller refused: {0}")]
+    Chip(#[from] DriverError),
+}
+
+/// What [stats] reports about a single irq
+pub struct IrqInfo<'a> {
+    pub irq: Irq,
+    pub chip: &'static str,
+    pub hwirq: u32,
+    pub hartid: usize,
+    pub enabled: bool,
+    actions: &'a [Action],
+    counts: &'a [AtomicUsize; MAX_HARTS],
+}
+
+impl IrqInfo<'_> {
+    /// The names the irq was requested with, in the order its handlers run
+    pub fn actions(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.actions.iter().map(|action| action.name)
+    }
+
+    /// How many times the irq fired on every hart that took it at least once
+    pub fn counts(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .enumerate()
+            .filter(|&(_, count)| count > 0)
+    }
+}
+
+struct Action {
+    name: &'static str,
+    handler: Handler,
+    deferred: Option<Deferred>,
+    dev: usize,
+    /// set by the fast handler, and taken by [run_deferred]
+    pending: AtomicBool,
+}
+
+struct IrqDesc {
+    chip: &'static dyn IrqChip,
+    hwirq: u32,
+    /// the hart the irq is routed to
+    hartid: usize,
+    flags: IrqFlags,
+    enabled: AtomicBool,
+    actions: Vec<Action>,
+    /// interrupts taken on harts with an id of [MAX_HARTS] and up are not counted
+    counts: [AtomicUsize; MAX_HARTS],
+}
+
+struct Irqs {
+    descs: BTreeMap<Irq, IrqDesc>,
+    /// by address of the chip, and hardware interrupt number
+    by_hwirq: BTreeMap<(usize, u32), Irq>,
+    next: Irq,
+}
+
+impl Irqs {
+    const fn new() -> Self {
+        Self {
+            descs: BTreeMap::new(),
+            by_hwirq: BTreeMap::new(),
+            next: 1,
+        }
+    }
+}
+
+/// The address of a chip, to tell chips apart
+fn chip_key(chip: &'static dyn IrqChip) -> usize {
+    chip as *const dyn IrqChip as *const () as usize
+}
+
+/// The irq behind `hwirq` of `chip`, creating it if it does not exist yet
+pub fn map(chip: &'static dyn IrqChip, hwirq: u32) -> Irq {
+    let key = (chip_key(chip), hwirq);
+
+    riscv::interrupt::free(|| {
+        let mut irqs = IRQS.write();
+        if let Some(&irq) = irqs.by_hwirq.get(&key) {
+            return irq;
+        }
+
+        let irq = irqs.next;
+        irqs.next += 1;
+
+        let desc = IrqDesc {
+            chip,
+            hwirq,
+            hartid: riscv::hartid(),
+            flags: IrqFlags::empty(),
+            enabled: AtomicBool::new(false),
+            actions: Vec::new(),
+            counts: [const { AtomicUsize::new(0) }; MAX_HARTS],
+        };
+
+        irqs.descs.insert(irq, desc);
+        irqs.by_hwirq.insert(key, irq);
+        irq
+    })
+}
+
+/// Make `chip` the controller that [wired] maps through, the first one to call this wins
+pub fn set_wired_chip(chip: &'static dyn IrqChip) {
+    WIRED.call_once(|| chip);
+}
+
+/// The irq behind a wired interrupt, as listed in the `interrupts` property of a device
+pub fn wired(hwirq: u32) -> Option<Irq> {
+    Some(map(*WIRED.get()?, hwirq))
+}
+
+/// Call `handler` whenever `irq` fires. The irq is enabled, and routed to the current hart if this
+/// is the first handler for it
+pub fn request_irq(
+    irq: Irq,
+    name: &'static str,
+    handler: Handler,
+    flags: IrqFlags,
+    dev: usize,
+) -> Result<(), IrqError> {
+    add_action(irq, flags, Action::new(name, handler, None, dev))
+}
+
+/// Like [request_irq], but `deferred` runs with interrupts enabled whenever `handler` returns
+/// [IrqReturn::Defer]
+pub fn request_deferred_irq(
+    irq: Irq,
+    name: &'static str,
+    handler: Handler,
+    deferred: Deferred,
+    flags: IrqFlags,
+    dev: usize,
+) -> Result<(), IrqError> {
+    add_action(irq, flags, Action::new(name, handler, Some(deferred), dev))
+}
+
+fn add_action(irq: Irq, flags: IrqFlags, action: Action) -> Result<(), IrqError> {
+    riscv::interrupt::free(|| {
+        let mut irqs = IRQS.write();
+        let desc = irqs.descs.get_mut(&irq).ok_or(IrqError::NoSuchIrq(irq))?;
+
+        if desc.actions.is_empty() {
+            desc.hartid = riscv::hartid();
+            desc.chip.enable(desc.hwirq, desc.hartid)?;
+            desc.enabled.store(true, Ordering::Relaxed);
+            desc.flags = flags;
+        } else if !(desc.flags & flags).contains(IrqFlags::SHARED) {
+            return Err(IrqError::Busy(irq));
+        }
+
+        desc.actions.push(action);
+        Ok(())
+    })
+}
+
+/// Remove the handler `dev` requested on `irq`. The irq is disabled once nobody is left using it
+#[allow(unused)]
+pub fn free_irq(irq: Irq, dev: usize) -> Result<(), IrqError> {
+    riscv::interrupt::free(|| {
+        let mut irqs = IRQS.write();
+        let desc = irqs.descs.get_mut(&irq).ok_or(IrqError::NoSuchIrq(irq))?;
+
+        let index = desc
+            .actions
+            .iter()
+            .position(|action| action.dev == dev)
+            .ok_or(IrqError::NotRequested(irq))?;
+        desc.actions.remove(index);
+
+        if desc.actions.is_empty() {
+            desc.enabled.store(false, Ordering::Relaxed);
+            desc.chip.disable(desc.hwirq);
+        }
+
+        Ok(())
+    })
+}
+
+/// Let `irq` through again, after a [disable]
+#[allow(unused)]
+pub fn enable(irq: Irq) -> Result<(), IrqError> {
+    riscv::interrupt::free(|| {
+        let irqs = IRQS.read();
+        let desc = irqs.descs.get(&irq).ok_or(IrqError::NoSuchIrq(irq))?;
+
+        desc.chip.enable(desc.hwirq, desc.hartid)?;
+        desc.enabled.store(true, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+/// Keep `irq` from firing, without removing its handlers. Safe to call from a fast handler
+#[allow(unused)]
+pub fn disable(irq: Irq) -> Result<(), IrqError> {
+    riscv::interrupt::free(|| {
+        let irqs = IRQS.read();
+        let desc = irqs.descs.get(&irq).ok_or(IrqError::NoSuchIrq(irq))?;
+
+        desc.enabled.store(false, Ordering::Relaxed);
+        desc.chip.disable(desc.hwirq);
+        Ok(())
+    })
+}
+
+/// Run the fast handlers of the irq behind `hwirq`, called by a chip in trap context
+pub fn dispatch(chip: &'static dyn IrqChip, hwirq: u32) {
+    // nothing in here may allocate, the allocator is not safe to use from trap context
+    let irqs = IRQS.read();
+    let desc = irqs
+        .by_hwirq
+        .get(&(chip_key(chip), hwirq))
+        .and_then(|irq| Some((*irq, irqs.descs.get(irq)?)));
+
+    let Some((irq, desc)) = desc else {
+        log::warn!("[IRQ] spurious interrupt {hwirq} from {}", chip.name());
+        return;
+    };
+
+    if let Some(count) = desc.counts.get(riscv::hartid()) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if !desc.enabled.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut handled = false;
+    for action in &desc.actions {
+        match (action.handler)(irq, action.dev) {
+            IrqReturn::None => {}
+            IrqReturn::Handled => handled = true,
+            IrqReturn::Defer => {
+                handled = true;
+                if action.deferred.is_some() {
+                    action.pending.store(true, Ordering::Release);
+                    DEFERRED.store(true, Ordering::Release);
+                }
+            }
+        }
+    }
+
+    if !handled {
+        log::warn!("[IRQ] nobody handled irq {irq}");
+    }
+}
+
+/// Run the deferred handlers that have work to do. Has to be called with interrupts enabled
+pub fn run_deferred() {
+    if !DEFERRED.swap(false, Ordering::Acquire) {
+        return;
+    }
+
+    // the handlers could request or free irqs, so they run without holding the lock
+    let work: Vec<(Deferred, Irq, usize)> = IRQS
+        .read()
+        .descs
+        .iter()
+        .flat_map(|(&irq, desc)| desc.actions.iter().map(move |action| (irq, action)))
+        .filter(|(_, action)| action.pending.swap(false, Ordering::Acquire))
+        .filter_map(|(irq, action)| Some((action.deferred?, irq, action.dev)))
+        .collect();
+
+    for (deferred, irq, dev) in work {
+        deferred(irq, dev);
+    }
+}
+
+/// Wait for an interrupt, and run any deferred work it brings with it
+pub fn idle() -> ! {
+    loop {
+        // checking for work and going to sleep cannot be interrupted, or we could sleep on work.
+        // A pending interrupt still wakes the hart up, and is taken once they are enabled again
+        riscv::interrupt::free(|| {
+            if !DEFERRED.load(Ordering::Acquire) {
+                riscv::wfi();
+            }
+        });
+
+        run_deferred();
+    }
+}
+
+/// Call `visit` with what we know about every irq that has been mapped. It does not allocate, and
+/// gives up (returning false) if the irqs are being changed, so the monitor can use it after a
+/// fatal error
+pub fn stats(mut visit: impl FnMut(&IrqInfo)) -> bool {
+    let Some(irqs) = IRQS.try_read() else {
+        return false;
+    };
+
+    for (&irq, desc) in &irqs.descs {
+        visit(&IrqInfo {
+            irq,
+            chip: desc.chip.name(),
+            hwirq: desc.hwirq,
+            hartid: desc.hartid,
+            enabled: desc.enabled.load(Ordering::Relaxed),
+            actions: &desc.actions,
+            counts: &desc.counts,
+        });
+    }
+
+    true
+}
+
+impl Action {
+    fn new(name: &'static str, handler: Handler, deferred: Option<Deferred>, dev: usize) -> Self {
+        Self {
+            name,
+            handler,
+            deferred,
+            dev,
+            pending: AtomicBool::new(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestChip {
+        enabled: AtomicBool,
+    }
+
+    impl IrqChip for TestChip {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn enable(&self, _hwirq: u32, _hartid: usize) -> Result<(), DriverError> {
+            self.enabled.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn disable(&self, _hwirq: u32) {
+            self.enabled.store(false, Ordering::Relaxed);
+        }
+    }
+
+    static CHIP: TestChip = TestChip {
+        enabled: AtomicBool::new(false),
+    };
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    static DEFERRED_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(_irq: Irq, dev: usize) -> IrqReturn {
+        CALLS.fetch_add(dev, Ordering::Relaxed);
+        IrqReturn::Handled
+    }
+
+    fn defer(_irq: Irq, _dev: usize) -> IrqReturn {
+        IrqReturn::Defer
+    }
+
+    fn deferred(_irq: Irq, dev: usize) {
+        DEFERRED_CALLS.fetch_add(dev, Ordering::Relaxed);
+    }
+
+    fn fire(hwirq: u32) {
+        riscv::interrupt::free(|| dispatch(&CHIP, hwirq));
+    }
+
+    #[test_case]
+    fn shared_lines() {
+        let irq = map(&CHIP, 1);
+        assert_eq!(map(&CHIP, 1), irq);
+
+        request_irq(irq, "first", count, IrqFlags::SHARED, 1).unwrap();
+        assert!(CHIP.enabled.load(Ordering::Relaxed));
+        request_irq(irq, "second", count, IrqFlags::SHARED, 10).unwrap();
+
+        let before = CALLS.load(Ordering::Relaxed);
+        fire(1);
+        assert_eq!(CALLS.load(Ordering::Relaxed) - before, 11);
+
+        free_irq(irq, 1).unwrap();
+        assert!(CHIP.enabled.load(Ordering::Relaxed));
+        free_irq(irq, 10).unwrap();
+        assert!(!CHIP.enabled.load(Ordering::Relaxed));
+        assert!(free_irq(irq, 10).is_err());
+
+        let mut counts = None;
+        assert!(stats(|info| {
+            if info.irq == irq {
+                counts = Some(info.counts().collect::<Vec<_>>());
+            }
+        }));
+        assert_eq!(counts.unwrap(), [(riscv::hartid(), 1)]);
+    }
+
+    #[test_case]
+    fn exclusive_lines() {
+        let irq = map(&CHIP, 2);
+
+        request_irq(irq, "first", count, IrqFlags::empty(), 1).unwrap();
+        assert!(request_irq(irq, "second", count, IrqFlags::SHARED, 2).is_err());
+        free_irq(irq, 1).unwrap();
+    }
+
+    #[test_case]
+    fn deferred_work() {
+        let irq = map(&CHIP, 3);
+        request_deferred_irq(irq, "deferred", defer, deferred, IrqFlags::empty(), 1).unwrap();
+
+        let before = DEFERRED_CALLS.load(Ordering::Relaxed);
+        fire(3);
+        assert_eq!(DEFERRED_CALLS.load(Ordering::Relaxed), before);
+
+        run_deferred();
+        assert_eq!(DEFERRED_CALLS.load(Ordering::Relaxed), before + 1);
+
+        run_deferred();
+        assert_eq!(DEFERRED_CALLS.load(Ordering::Relaxed), before + 1);
+
+        free_irq(irq, 1).unwrap();
+    }
+}
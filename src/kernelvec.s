@@ -1,15 +1,23 @@
 .section .text.trap
 .global ktrapvec
 
-# We send the stack pointer in as the first argument to the kernel trap handler.
-# This allows us to use that value in order to load the saved registers into a
-# struct. We save the registers in the order of their internal names (x0-31),
-# and not in the order of their ABI names (e.g. saving t0-6 then a0-7 ...)
-# TODO: save floating point registers
-ktrapvec:
-allocspace:
-    addi sp, sp, -8*30 # -240
-save:
+# these come from vmem.rs and stack.rs, see main.rs
+.equ KSTACKS_BASE, {kstacks_base}
+.equ KSTACKS_SIZE, {kstacks_size}
+.equ KSTACK_SLOT, {kstack_slot}
+# the guard pages start one page into the slot, right above the emergency stack
+.equ KSTACK_GUARD, {kstack_guard}
+.equ KSTACK_GUARD_SIZE, {kstack_guard_size}
+
+# the trap frame, 31 registers and 4 CSRs. These come from riscv::Frame, see main.rs
+.equ FRAME_SIZE, {frame_size}
+.equ FRAME_SP, {frame_sp}
+.equ FRAME_SEPC, {frame_sepc}
+.equ FRAME_SSTATUS, {frame_sstatus}
+.equ FRAME_SCAUSE, {frame_scause}
+.equ FRAME_STVAL, {frame_stval}
+
+.macro save_regs
     sd ra, 0(sp)
     sd sp, 8(sp)
     sd gp, 16(sp)
@@ -41,12 +49,22 @@ save:
     sd t4, 224(sp)
     sd t5, 232(sp)
     sd t6, 240(sp)
-calltrap:
-    mv a0, sp
-    call kerneltrap
-load:
+.endm
+
+# needs the general purpose registers saved, as it overwrites t0
+.macro save_csrs
+    csrr t0, sepc
+    sd t0, FRAME_SEPC(sp)
+    csrr t0, sstatus
+    sd t0, FRAME_SSTATUS(sp)
+    csrr t0, scause
+    sd t0, FRAME_SCAUSE(sp)
+    csrr t0, stval
+    sd t0, FRAME_STVAL(sp)
+.endm
+
+.macro load_regs
     ld ra, 0(sp)
-    ld sp, 8(sp)
     ld gp, 16(sp)
     ld tp, 24(sp)
     ld t0, 32(sp)
@@ -76,7 +94,73 @@ load:
     ld t4, 224(sp)
     ld t5, 232(sp)
     ld t6, 240(sp)
-deallocspace:
-    addi sp, sp, 8*30 # 240
+    # last, as it is the base of the frame. The handler could have put any stack here
+    ld sp, FRAME_SP(sp)
+.endm
+
+# We send the stack pointer in as the first argument to the kernel trap handler.
+# This allows us to use that value in order to load the saved registers into a
+# struct. We save the registers in the order of their internal names (x0-31),
+# and not in the order of their ABI names (e.g. saving t0-6 then a0-7 ...),
+# followed by the trap CSRs. sepc and sstatus are restored from the frame, so
+# the handler can resume somewhere else by changing them
+# TODO: save floating point registers
+ktrapvec:
+checkstack:
+    # sscratch holds the top of this hart's emergency stack. We borrow two registers from it, to
+    # check if the trap frame would end up in the guard pages of a kernel stack
+    csrrw sp, sscratch, sp
+    sd t0, -8(sp)
+    sd t1, -16(sp)
+    csrr t1, sscratch
+    addi t1, t1, -FRAME_SIZE
+    li t0, KSTACKS_BASE
+    sub t1, t1, t0
+    li t0, KSTACKS_SIZE
+    bgeu t1, t0, stackok
+    li t0, KSTACK_SLOT - 1
+    and t1, t1, t0
+    li t0, KSTACK_GUARD
+    sub t1, t1, t0
+    li t0, KSTACK_GUARD_SIZE
+    bltu t1, t0, stackoverflow
+stackok:
+    # back to the interrupted stack, with sscratch pointing to the emergency stack again
+    mv t0, sp
+    csrrw sp, sscratch, t0
+    ld t1, -16(t0)
+    ld t0, -8(t0)
+allocspace:
+    addi sp, sp, -FRAME_SIZE
+save:
+    save_regs
+    save_csrs
+    # the frame should have the stack pointer we were interrupted with
+    addi t0, sp, FRAME_SIZE
+    sd t0, FRAME_SP(sp)
+calltrap:
+    mv a0, sp
+    call kerneltrap
+load:
+    ld t0, FRAME_SEPC(sp)
+    csrw sepc, t0
+    ld t0, FRAME_SSTATUS(sp)
+    csrw sstatus, t0
+    # this frees the frame too
+    load_regs
 ret_to_supervisor:
     sret
+
+# The stack we were using is gone, so the frame is saved on the emergency stack instead. There is
+# no coming back from this
+stackoverflow:
+    ld t0, -8(sp)
+    ld t1, -16(sp)
+    addi sp, sp, -16-FRAME_SIZE
+    save_regs
+    save_csrs
+    # the frame should have the stack pointer we were interrupted with
+    csrr t0, sscratch
+    sd t0, FRAME_SP(sp)
+    mv a0, sp
+    call kernel_stack_overflow
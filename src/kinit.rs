@@ -1,35 +1,85 @@
 //! Second stage of the kernel's init
 
-use crate::allocator::BitMapAlloc;
-use crate::riscv::{self, sbi};
-use crate::{PAGE_SIZE, STACK_PAGES, vmem};
+use crate::drivers::imsic::Imsic;
+use crate::irq;
+use crate::monitor;
+use crate::percpu;
+use crate::riscv;
+use crate::smp;
+use crate::time;
+use crate::vmem::{self, Mapper};
 
-const STACK_SIZE: usize = STACK_PAGES * PAGE_SIZE;
+/// 1. Allocate stacks and per-CPU blocks for all available harts, and start them
+/// 2. Move the boot hart onto its own stack, and continue with [kinit]
+pub fn pre_kinit(fdt: fdt::Fdt, mapper: &mut Mapper, hartid: usize) -> ! {
+    let mut boot_stack = None;
 
-/// 1. Allocate stacks for all available harts
-pub fn pre_kinit(balloc: &mut BitMapAlloc, fdt: fdt::Fdt) {
-    let cpu_count = fdt.cpus().count();
+    // hart ids do not have to start at zero, or be next to each other
+    for cpu in fdt.cpus() {
+        let id = cpu.ids().first();
 
-    for id in 0..cpu_count {
-        let addr = balloc.alloc(STACK_PAGES);
-        // the address we are returned is at the top of the allocated space, we need to go lower
-        let stack_bottom = addr + STACK_SIZE;
-        sbi::hsm::start(id, _start as *const () as usize, stack_bottom);
+        let status = cpu.property("status").and_then(|status| status.as_str());
+        if status.is_some_and(|status| !matches!(status, "okay" | "ok")) {
+            log::info!("[SMP] hart#{id} is not available, skipping it");
+            continue;
+        }
+
+        if id >= crate::MAX_HARTS {
+            log::warn!("[SMP] hart#{id} is above MAX_HARTS, skipping it");
+            continue;
+        }
+
+        let stack_top = crate::stack::alloc(mapper, id).expect("could not allocate hart stack");
+
+        if id == hartid {
+            boot_stack = Some(stack_top);
+            continue;
+        }
+
+        let cpu = percpu::alloc(id, stack_top);
+        match smp::start_hart(id, cpu) {
+            Ok(()) => log::debug!("[SMP] started hart#{id}"),
+            Err(err) => {
+                log::warn!("[SMP] could not start hart#{id}: {err}");
+
+                // a hart that timed out might still come up later, and would need them
+                if !matches!(err, smp::HartError::Timeout { .. }) {
+                    percpu::free(id);
+                    crate::stack::free(mapper, id);
+                }
+            }
+        }
     }
+
+    let stack_top = boot_stack.expect("the boot hart is not in the fdt");
+    riscv::sfence_vma();
+    // safety: the stack was just mapped, and nothing we have on the boot stack is needed anymore.
+    // The boot hart keeps the per-CPU block it got in entry.s
+    unsafe { enter_hart(riscv::tp(), stack_top) }
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn kinit(hartid: usize) -> ! {
+pub extern "C" fn kinit() -> ! {
+    let hartid = riscv::hartid();
+
     // safety: cannot be used in critical section
     unsafe { riscv::interrupt::enable_all() };
-    crate::trap::reset_timer();
+    time::inithart();
 
     vmem::inithart();
+    Imsic::inithart();
+    smp::set_online();
+
+    // the boot hart takes the device interrupts, so it is the one that waits on the console
+    if hartid == smp::boot_hart() {
+        monitor::run();
+    }
 
     log::trace!("[HART#{hartid}] Entering loop...");
-    riscv::pauseloop();
+    irq::idle();
 }
 
 unsafe extern "C" {
-    fn _start();
+    /// Switch to the given stack (and its emergency stack), and jump to [kinit]
+    fn enter_hart(cpu: usize, stack_top: usize) -> !;
 }
@@ -7,100 +7,155 @@
 extern crate alloc;
 
 mod allocator;
+mod backtrace;
 mod drivers;
+mod irq;
 mod kinit;
+mod monitor;
+mod percpu;
+mod pmem;
 mod proc;
 mod riscv;
+mod smp;
+mod stack;
 mod symbols;
+mod sync;
 mod systems;
+mod time;
 mod trap;
+mod tty;
 mod vmem;
 mod writer;
 
 use core::panic::PanicInfo;
 
-use linked_list_allocator::LockedHeap;
-use spin::Mutex;
-
-use crate::allocator::BitMapAlloc;
+use crate::allocator::GBMAlloc;
+use crate::drivers::DriverError;
+use crate::drivers::aplic::Aplic;
+use crate::drivers::imsic::Imsic;
+use crate::drivers::plic::Plic;
 use crate::drivers::uart::CharDriver;
 use crate::systems::pci::PciSubsystem;
 use crate::vmem::{Mapper, Perms};
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: GBMAlloc = GBMAlloc;
 
-pub const INTERVAL: usize = 8000000;
 pub const PAGE_SIZE: usize = 0x1000; // 4096
-pub const HEAP1_SIZE: usize = 1024 * 1024 * 1024;
-pub const STACK_PAGES: usize = 1;
+pub const STACK_PAGES: usize = 4;
+/// Harts with a higher id are not brought up
+pub const MAX_HARTS: usize = 64;
 
 #[unsafe(no_mangle)]
 extern "C" fn start(hartid: usize, fdt_ptr: usize) -> ! {
     println!("\n\n\n^w^ welcome to my operating system");
+    percpu::init();
+    smp::set_boot_hart();
     writer::init_log();
+    riscv::sbi::init();
 
     log::debug!("KERNEL STARTING ON HART#{hartid}");
 
-    let balloc = init_heap();
+    // safety: the fdt_ptr needs to be valid. this is "guaranteed" by OpenSBI. We are given its
+    // physical address, the boot page table has it in the direct map
+    let fdt_addr = vmem::phys_to_virt(fdt_ptr);
+    let fdt = unsafe { fdt::Fdt::from_ptr(fdt_addr as *const u8) }.expect("could not parse fdt");
+
+    // log lines have timestamps from here on
+    time::init(fdt).expect("could not find the timebase frequency");
+
+    // physical pages are handed out by the buddy allocator, which also backs the global allocator
+    let memory = pmem::init(fdt, fdt_ptr);
 
-    // safety: the fdt_ptr needs to be valid. this is "guaranteed" by OpenSBI
-    let fdt = unsafe { fdt::Fdt::from_ptr(fdt_ptr as *const u8) }.expect("could not parse fdt");
+    let mut mapper = vmem::init();
 
-    let mut balloc = balloc.lock();
-    let mut mapper = vmem::init(&mut balloc);
+    // map the kernel, stack and the rest of RAM onto the memory
+    map_vitals(&mut mapper, memory).expect("could not map vital memory");
+    // leave the boot page table behind, the other harts switch over as soon as they start
+    vmem::inithart();
+    // large allocations can be mapped lazily from here on
+    allocator::vheap::init();
 
-    // map the kernel, stack and the heap onto the memory
-    map_vitals(&mut mapper).expect("could not map vital memory");
     // init PCIe and the most essential drivers
     init_drivers(fdt, &mut mapper);
 
-    #[cfg(test)]
-    test_main();
-
-    kinit::pre_kinit(&mut balloc, fdt);
-    kinit::kinit(hartid);
-}
+    allocator::heap_stats().pretty_print();
 
-fn init_heap() -> Mutex<BitMapAlloc> {
-    let balloc_addr = unsafe { symbols::HEAP0_TOP };
-    let balloc = allocator::BitMapAlloc::init(balloc_addr);
+    #[cfg(feature = "timer_latency")]
+    {
+        let latency = time::measure_rearm(1000);
+        log::info!("[TIME] re-arming the timer takes {latency:?} cycles");
+    }
 
-    // global allocator for `alloc`
-    let heap_start = unsafe { symbols::HEAP1_TOP as *mut u8 };
-    unsafe { ALLOCATOR.lock().init(heap_start, HEAP1_SIZE) }
+    #[cfg(test)]
+    test_main();
 
-    balloc
+    kinit::pre_kinit(fdt, &mut mapper, hartid);
 }
 
-fn map_vitals(mapper: &mut Mapper) -> Result<(), vmem::MapError> {
+fn map_vitals(mapper: &mut Mapper, memory: &pmem::PhysMemory) -> Result<(), vmem::MapError> {
     let kernel_start = unsafe { symbols::MEMTOP };
+    let kernel_end = unsafe { symbols::KERNEL_END };
 
-    // we are rounding up the etext
+    // every section starts on a new page, so we round the ends up
     let etext = round_up_by(unsafe { symbols::ETEXT }, PAGE_SIZE);
-    let kernel_pages = (etext - kernel_start) / 4096;
-
-    let heap1 = unsafe { symbols::HEAP1_TOP };
-    let heap1_pages = HEAP1_SIZE / PAGE_SIZE;
-
-    let stack_heap0_size = heap1 - etext;
-    let stack_heap0_pages = stack_heap0_size / PAGE_SIZE;
-
-    // MAP THE KERNEL
-    mapper.map(kernel_start, kernel_start, Perms::EXEC, kernel_pages)?;
-
-    // TODO: map the heap pages during allocation
+    let erodata = round_up_by(unsafe { symbols::ERODATA }, PAGE_SIZE);
+
+    // map a part of the kernel image at the address it is linked at
+    let mut map_kernel = |start: usize, end: usize, perms: Perms| {
+        let pages = (end - start) / PAGE_SIZE;
+        mapper.map(vmem::virt_to_phys(start), start, perms, pages)
+    };
+
+    // .text is the only part of the kernel that is executable, and nothing is both writable and
+    // executable
+    map_kernel(kernel_start, etext, Perms::READ_EXEC)?;
+    map_kernel(etext, erodata, Perms::READ)?;
+    // .data, .bss and the boot stack
+    map_kernel(erodata, kernel_end, Perms::READ_WRITE)?;
+
+    // MAP ALL OF RAM INTO THE DIRECT MAP, this is how we reach anything the frame allocator hands
+    // out (page tables, stacks and the heap)
+    let kernel_pa = vmem::virt_to_phys(kernel_start);
+    let kernel_end_pa = vmem::virt_to_phys(kernel_end);
+
+    let mut ram = memory.ram;
+    ram.subtract(kernel_pa, kernel_end_pa);
+
+    for region in ram.iter() {
+        let vaddr = vmem::phys_to_virt(region.start);
+        mapper.map(region.start, vaddr, Perms::READ_WRITE, region.pages())?;
+    }
 
-    // MAP STACK AND HEAP0
-    mapper.map(etext, etext, Perms::READ_WRITE, stack_heap0_pages)?;
-    // MAP HEAP1
-    mapper.map(heap1, heap1, Perms::READ_WRITE, heap1_pages)?;
+    // the kernel image shows up in the direct map too, but we never write to it through there
+    let kernel_pages = (kernel_end_pa - kernel_pa) / PAGE_SIZE;
+    mapper.map(
+        kernel_pa,
+        vmem::phys_to_virt(kernel_pa),
+        Perms::READ,
+        kernel_pages,
+    )?;
 
     Ok(())
 }
 
 fn init_drivers(fdt: fdt::Fdt, mapper: &mut Mapper) {
-    CharDriver::init(fdt, mapper).expect("could not init uart driver");
+    // interrupt controllers go first, so the drivers after them can register their interrupts.
+    // A platform has either a PLIC, or an APLIC sending messages to IMSICs
+    let aia = Imsic::init(fdt).and_then(|()| Aplic::init(fdt, mapper));
+    match aia {
+        Ok(()) => {}
+        Err(DriverError::DeviceNotFound) => {
+            if let Err(err) = Plic::init(fdt, mapper) {
+                log::warn!("[PLIC] not initialised: {err}");
+            }
+        }
+        Err(err) => log::warn!("[AIA] not initialised: {err}"),
+    }
+
+    if let Err(err) = CharDriver::init(fdt, mapper) {
+        log::warn!("[UART] not initialised, staying on the SBI console: {err}");
+    }
 
     // we setup pcie subsystem along with some basic drivers
     let mut pci = PciSubsystem::init(fdt, mapper).expect("could not initialise PCI");
@@ -122,18 +177,22 @@ pub fn round_down_by(input: usize, alignment: usize) -> usize {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     riscv::interrupt::disable();
+    writer::set_fatal();
 
     #[cfg(test)]
     println!("[TEST FAILED]");
     println!("{}", info);
+    backtrace::print();
 
     #[cfg(test)]
     {
         use riscv::sbi::srst::*;
         system_reset(ResetType::Shutdown, ResetReason::Failure);
+        riscv::pauseloop();
     }
 
-    riscv::pauseloop();
+    #[cfg(not(test))]
+    monitor::enter_fatal();
 }
 
 #[cfg(test)]
@@ -156,6 +215,24 @@ pub fn test_runner(tests: &[&dyn Fn()]) -> ! {
 }
 
 // ========= ASSEMBLY IMPORTS =========
-include_asm!("kernelvec.s");
-include_asm!("entry.s");
+include_asm!(
+    "kernelvec.s",
+    kstacks_base = const vmem::KSTACKS_BASE,
+    kstacks_size = const stack::REGION_SIZE,
+    kstack_slot = const stack::SLOT_SIZE,
+    kstack_guard = const stack::GUARD_START,
+    kstack_guard_size = const stack::GUARD_END - stack::GUARD_START,
+    frame_size = const riscv::FRAME_SIZE,
+    frame_sp = const core::mem::offset_of!(riscv::Frame, sp),
+    frame_sepc = const core::mem::offset_of!(riscv::Frame, sepc),
+    frame_sstatus = const core::mem::offset_of!(riscv::Frame, sstatus),
+    frame_scause = const core::mem::offset_of!(riscv::Frame, scause),
+    frame_stval = const core::mem::offset_of!(riscv::Frame, stval),
+);
+include_asm!(
+    "entry.s",
+    kstack_slot = const stack::SLOT_SIZE,
+    cpu_hartid = const percpu::CPU_HARTID,
+    cpu_stack_top = const percpu::CPU_STACK_TOP,
+);
 // ====================================
@@ -0,0 +1,608 @@
+//! The kernel monitor, a shell on the console to look around a running (or dying) kernel
+//!
+//! The boot hart runs it once the kernel is up, and it takes over the console after a panic or a
+//! fatal exception. Commands implement [Command], the built-in ones are below and subsystems can
+//! [register] their own.
+
+use alloc::vec::Vec;
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::RwLock;
+
+use crate::allocator::{self, FRAME_ALLOC};
+use crate::irq;
+use crate::percpu;
+use crate::riscv::{
+    self,
+    sbi::{
+        SbiError,
+        hsm::{self, HartState},
+        srst::{self, ResetReason, ResetType},
+    },
+};
+use crate::smp;
+use crate::systems::pci;
+use crate::tty::{Tty, TtyError};
+use crate::vmem::{self, Perms};
+use crate::writer;
+use crate::{MAX_HARTS, PAGE_SIZE};
+
+/// Words `peek` prints if it is not told how many
+const PEEK_WORDS: usize = 4;
+/// Words `peek` prints on a line
+const PEEK_PER_LINE: usize = 4;
+/// Longest line that can be typed in after a fatal error
+const LINE_MAX: usize = 128;
+/// Most words a command line can have, its name included
+const MAX_WORDS: usize = 8;
+/// Width of the name and usage column of `help`
+const HELP_COLUMN: usize = 44;
+
+/// Commands registered on top of the built-in ones
+static COMMANDS: RwLock<Vec<&'static dyn Command>> = RwLock::new(Vec::new());
+/// Set by the first hart to enter the monitor after a fatal error
+static FATAL: AtomicBool = AtomicBool::new(false);
+
+static BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "help",
+        usage: "",
+        summary: "list every command",
+        run: help,
+    },
+    Builtin {
+        name: "mem",
+        usage: "",
+        summary: "usage of physical memory and the heap",
+        run: mem,
+    },
+    Builtin {
+        name: "pt",
+        usage: "<vaddr>",
+        summary: "walk the page table for a virtual address",
+        run: pt,
+    },
+    Builtin {
+        name: "lspci",
+        usage: "",
+        summary: "list the PCI devices found at boot",
+        run: lspci,
+    },
+    Builtin {
+        name: "harts",
+        usage: "",
+        summary: "state and interrupt counters of every hart",
+        run: harts,
+    },
+    Builtin {
+        name: "irq",
+        usage: "",
+        summary: "every irq, where it is routed and how often it fired",
+        run: irqs,
+    },
+    Builtin {
+        name: "peek",
+        usage: "<paddr> [words]",
+        summary: "read 32 bit words of physical memory",
+        run: peek,
+    },
+    Builtin {
+        name: "poke",
+        usage: "<paddr> <value>",
+        summary: "write a 32 bit word of physical memory",
+        run: poke,
+    },
+    Builtin {
+        name: "log",
+        usage: "[off|error|warn|info|debug|trace]",
+        summary: "show or set the log level",
+        run: log_level,
+    },
+    Builtin {
+        name: "reboot",
+        usage: "",
+        summary: "reset the machine",
+        run: reboot,
+    },
+    Builtin {
+        name: "shutdown",
+        usage: "",
+        summary: "power the machine off",
+        run: shutdown,
+    },
+];
+
+/// Something that can be typed into the monitor
+pub trait Command: Sync {
+    /// What the command is typed as
+    fn name(&self) -> &'static str;
+
+    /// The arguments it takes, for `help`
+    fn usage(&self) -> &'static str {
+        ""
+    }
+
+    /// What it does, in one line
+    fn summary(&self) -> &'static str;
+
+    /// Run the command, `args` are the words typed after its name
+    fn run(&self, args: &[&str]) -> Result<(), MonitorError>;
+}
+
+/// Nothing in here is allocated, commands also run after a fatal error
+#[derive(Debug, thiserror::Error)]
+pub enum MonitorError {
+    #[error("no such command, try help")]
+    NoSuchCommand,
+    #[error("wrong arguments")]
+    Usage,
+    #[error("too many words, at most {MAX_WORDS} are taken")]
+    TooManyWords,
+    #[error("not a number")]
+    NotANumber,
+    #[error("not a log level")]
+    NotALevel,
+    #[error("{addr:#x} is not aligned to {align} bytes")]
+    Unaligned { addr: usize, align: usize },
+    #[error("{0:#x} is not mapped")]
+    Unmapped(usize),
+    #[error("{0:#x} is mapped read-only")]
+    ReadOnly(usize),
+    #[error("there already is a command called {0}")]
+    AlreadyRegistered(&'static str),
+    #[error("sbi call failed: {0}")]
+    Sbi(#[from] SbiError),
+}
+
+/// A command that is just a function, this is what the built-in ones are
+pub struct Builtin {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub run: fn(&[&str]) -> Result<(), MonitorError>,
+}
+
+impl Command for Builtin {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn usage(&self) -> &'static str {
+        self.usage
+    }
+
+    fn summary(&self) -> &'static str {
+        self.summary
+    }
+
+    fn run(&self, args: &[&str]) -> Result<(), MonitorError> {
+        (self.run)(args)
+    }
+}
+
+/// Make `command` available in the monitor
+#[allow(unused)]
+pub fn register(command: &'static dyn Command) -> Result<(), MonitorError> {
+    let mut commands = COMMANDS.write();
+
+    let name = command.name();
+    if BUILTINS.iter().any(|builtin| builtin.name == name)
+        || commands.iter().any(|other| other.name() == name)
+    {
+        return Err(MonitorError::AlreadyRegistered(name));
+    }
+
+    commands.push(command);
+    Ok(())
+}
+
+/// The command called `name`. Registered commands are left out while one is being registered, a
+/// hart could have stopped in the middle of it
+fn find(name: &str) -> Option<&'static dyn Command> {
+    if let Some(builtin) = BUILTINS.iter().find(|builtin| builtin.name == name) {
+        return Some(builtin);
+    }
+
+    COMMANDS
+        .try_read()?
+        .iter()
+        .copied()
+        .find(|command| command.name() == name)
+}
+
+/// Run the command typed in on `line`. Empty lines do nothing
+pub fn execute(line: &str) -> Result<(), MonitorError> {
+    let mut words = [""; MAX_WORDS];
+    let mut count = 0;
+    for word in line.split_whitespace() {
+        *words.get_mut(count).ok_or(MonitorError::TooManyWords)? = word;
+        count += 1;
+    }
+
+    let [name, args @ ..] = &words[..count] else {
+        return Ok(());
+    };
+    let command = find(name).ok_or(MonitorError::NoSuchCommand)?;
+
+    match command.run(args) {
+        Err(MonitorError::Usage) => {
+            crate::println!("usage: {name} {}", command.usage());
+            Err(MonitorError::Usage)
+        }
+        result => result,
+    }
+}
+
+/// Run the monitor on the console, for as long as the kernel runs
+pub fn run() -> ! {
+    crate::println!("kernel monitor, type help for a list of commands");
+
+    let mut tty = Tty::console();
+    loop {
+        let line = tty.read_line("> ");
+        report(line.as_deref().map_err(|err| *err));
+    }
+}
+
+/// Take over the console after the kernel ran into something it cannot continue from. This can be
+/// in trap context, before the heap is up, or with any lock held. Nothing in here allocates, output
+/// goes around the console lock if it is taken (see [writer::set_fatal]), input is polled from the
+/// device and the commands only try the locks they need. The first hart to get here stops the
+/// others, and keeps interrupts off. Harts that get here after it halt
+pub fn enter_fatal() -> ! {
+    riscv::interrupt::disable();
+    writer::set_fatal();
+    if FATAL.swap(true, Ordering::AcqRel) {
+        smp::halt();
+    }
+    smp::halt_others();
+
+    crate::println!("\nentering the kernel monitor, reboot or shutdown to leave it");
+
+    let mut tty = Tty::polled_console();
+    let mut buf = [0; LINE_MAX];
+    loop {
+        report(tty.read_line_into("(fatal) > ", &mut buf));
+    }
+}
+
+/// Run the line that was read, if any, and print what went wrong
+fn report(line: Result<&str, TtyError>) {
+    match line {
+        // the usage has been printed already
+        Ok(line) => match execute(line) {
+            Ok(()) | Err(MonitorError::Usage) => {}
+            Err(err) => crate::println!("{err}"),
+        },
+        Err(TtyError::EndOfFile) => crate::println!("use shutdown or reboot to leave"),
+        Err(_) => {}
+    }
+}
+
+/// A number in decimal, or in hex with a leading `0x`. Underscores are skipped
+fn parse_number(word: &str) -> Result<usize, MonitorError> {
+    let (digits, radix) = match word.strip_prefix("0x") {
+        Some(hex) => (hex, 16),
+        None => (word, 10),
+    };
+
+    let mut digits = digits.chars().filter(|&c| c != '_').peekable();
+    if digits.peek().is_none() {
+        return Err(MonitorError::NotANumber);
+    }
+
+    digits.try_fold(0usize, |value, c| {
+        let digit = c.to_digit(radix).ok_or(MonitorError::NotANumber)?;
+        value
+            .checked_mul(radix as usize)
+            .and_then(|value| value.checked_add(digit as usize))
+            .ok_or(MonitorError::NotANumber)
+    })
+}
+
+/// Where the word at `paddr` can be reached in the direct map, if it is mapped with `perms`
+fn direct_map(paddr: usize, perms: Perms) -> Result<usize, MonitorError> {
+    if !paddr.is_multiple_of(4) {
+        return Err(MonitorError::Unaligned {
+            addr: paddr,
+            align: 4,
+        });
+    }
+
+    let vaddr = vmem::phys_to_virt(paddr);
+    match vmem::translate(vaddr) {
+        Some((_, mapped)) if mapped.contains(perms) => Ok(vaddr),
+        Some(_) => Err(MonitorError::ReadOnly(paddr)),
+        None => Err(MonitorError::Unmapped(paddr)),
+    }
+}
+
+fn help(_args: &[&str]) -> Result<(), MonitorError> {
+    let print = |command: &dyn Command| {
+        let name = command.name();
+        let width = HELP_COLUMN.saturating_sub(name.len() + 1);
+        crate::println!("  {name} {:<width$} {}", command.usage(), command.summary());
+    };
+
+    BUILTINS.iter().for_each(|builtin| print(builtin));
+    match COMMANDS.try_read() {
+        Some(commands) => commands.iter().for_each(|&command| print(command)),
+        None => crate::println!("  (other commands are being registered)"),
+    }
+
+    Ok(())
+}
+
+fn mem(_args: &[&str]) -> Result<(), MonitorError> {
+    let Some((free, total, blocks)) = FRAME_ALLOC.try_lock().map(|frames| {
+        (
+            frames.free_pages(),
+            frames.total_pages(),
+            frames.free_blocks(),
+        )
+    }) else {
+        crate::println!("frames: the frame allocator is in use");
+        allocator::heap_stats().pretty_print();
+        return Ok(());
+    };
+
+    crate::println!(
+        "frames: {} of {} pages free ({} of {} KiB)",
+        free,
+        total,
+        free * PAGE_SIZE / 1024,
+        total * PAGE_SIZE / 1024,
+    );
+
+    let orders = blocks.iter().enumerate().filter(|&(_, &count)| count > 0);
+    for (order, count) in orders {
+        crate::println!("  order {order:>2}: {count} free blocks");
+    }
+
+    allocator::heap_stats().pretty_print();
+    Ok(())
+}
+
+fn pt(args: &[&str]) -> Result<(), MonitorError> {
+    let [vaddr] = args else {
+        return Err(MonitorError::Usage);
+    };
+    let vaddr = parse_number(vaddr)?;
+
+    crate::println!("walk for {vaddr:#x}, root at {:#x}:", vmem::active_root());
+    vmem::walk_entries(vaddr, |step| crate::println!("  {step}"));
+
+    match vmem::translate(vaddr) {
+        Some((paddr, perms)) => crate::println!("{vaddr:#x} -> {paddr:#x} {perms:?}"),
+        None => crate::println!("{vaddr:#x} is not mapped"),
+    }
+
+    Ok(())
+}
+
+fn lspci(_args: &[&str]) -> Result<(), MonitorError> {
+    let devices = pci::devices();
+    if devices.is_empty() {
+        crate::println!("no PCI devices");
+    }
+
+    for device in devices {
+        crate::println!(
+            "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}{:02x}{:02x} {:?}",
+            device.bus,
+            device.device,
+            device.func,
+            device.vendor_id,
+            device.device_id,
+            device.class_code,
+            device.subclass,
+            device.prog_if,
+            device.header_type,
+        );
+    }
+
+    Ok(())
+}
+
+fn harts(_args: &[&str]) -> Result<(), MonitorError> {
+    let online = smp::online();
+
+    for hartid in 0..MAX_HARTS {
+        let Some(cpu) = percpu::cpu_for(hartid) else {
+            continue;
+        };
+
+        let state = hsm::get_status(hartid).map_or("unknown", |state| match state {
+            HartState::Started => "started",
+            HartState::Stopped => "stopped",
+            HartState::StartPending => "starting",
+            HartState::StopPending => "stopping",
+            HartState::Suspended => "suspended",
+            HartState::SuspendPending => "suspending",
+            HartState::ResumePending => "resuming",
+        });
+        let stats = &cpu.stats;
+        let load = |count: &AtomicUsize| count.load(Ordering::Relaxed);
+
+        crate::println!(
+            "hart#{hartid}{} {state:<10} {} online, {} interrupts ({} timer, {} ipi), {} exceptions",
+            if hartid == riscv::hartid() { "*" } else { " " },
+            if online & (1 << hartid) != 0 {
+                ""
+            } else {
+                "not"
+            },
+            load(&stats.interrupts),
+            load(&stats.timers),
+            load(&stats.ipis),
+            load(&stats.exceptions),
+        );
+    }
+
+    Ok(())
+}
+
+fn irqs(_args: &[&str]) -> Result<(), MonitorError> {
+    let complete = irq::stats(|info| {
+        let total: usize = info.counts().map(|(_, count)| count).sum();
+
+        crate::print!(
+            "irq {:>3}: {} hwirq {:<4} hart#{} {:<8} {total:>8} fired [",
+            info.irq,
+            info.chip,
+            info.hwirq,
+            info.hartid,
+            if info.enabled { "enabled" } else { "disabled" },
+        );
+        for (i, action) in info.actions().enumerate() {
+            crate::print!("{}{action}", if i == 0 { "" } else { ", " });
+        }
+        crate::println!("]");
+    });
+
+    if !complete {
+        crate::println!("the irqs are being changed, try again");
+    }
+
+    Ok(())
+}
+
+fn peek(args: &[&str]) -> Result<(), MonitorError> {
+    let (paddr, words) = match args {
+        [paddr] => (parse_number(paddr)?, PEEK_WORDS),
+        [paddr, words] => (parse_number(paddr)?, parse_number(words)?),
+        _ => return Err(MonitorError::Usage),
+    };
+
+    for line in (0..words).step_by(PEEK_PER_LINE) {
+        let start = paddr + line * 4;
+        crate::print!("{start:#014x}:");
+
+        for word in line..words.min(line + PEEK_PER_LINE) {
+            let vaddr = direct_map(paddr + word * 4, Perms::READ)?;
+            // safety: it is mapped, and aligned. Device registers can have side effects on read,
+            // which is up to whoever typed this in
+            let value = unsafe { core::ptr::read_volatile(vaddr as *const u32) };
+            crate::print!(" {value:08x}");
+        }
+        crate::println!();
+    }
+
+    Ok(())
+}
+
+fn poke(args: &[&str]) -> Result<(), MonitorError> {
+    let [paddr, value] = args else {
+        return Err(MonitorError::Usage);
+    };
+    let (paddr, value) = (parse_number(paddr)?, parse_number(value)?);
+    let value = u32::try_from(value).map_err(|_| MonitorError::NotANumber)?;
+
+    let vaddr = direct_map(paddr, Perms::READ_WRITE)?;
+    // safety: it is mapped writable, and aligned. Whatever it breaks is what was asked for
+    unsafe { core::ptr::write_volatile(vaddr as *mut u32, value) };
+
+    Ok(())
+}
+
+fn log_level(args: &[&str]) -> Result<(), MonitorError> {
+    match args {
+        [] => crate::println!("log level is {}", log::max_level()),
+        [level] => {
+            let filter = level.parse().map_err(|_| MonitorError::NotALevel)?;
+            log::set_max_level(filter);
+        }
+        _ => return Err(MonitorError::Usage),
+    }
+
+    Ok(())
+}
+
+fn reboot(_args: &[&str]) -> Result<(), MonitorError> {
+    Err(srst::system_reset(ResetType::ColdReboot, ResetReason::None).into())
+}
+
+fn shutdown(_args: &[&str]) -> Result<(), MonitorError> {
+    Err(srst::system_reset(ResetType::Shutdown, ResetReason::None).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::boxed::Box;
+
+    struct Echo;
+
+    impl Command for Echo {
+        fn name(&self) -> &'static str {
+            "test-echo"
+        }
+
+        fn summary(&self) -> &'static str {
+            "print the arguments"
+        }
+
+        fn run(&self, args: &[&str]) -> Result<(), MonitorError> {
+            crate::println!("{}", args.join(" "));
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    fn numbers() {
+        assert_eq!(parse_number("42").unwrap(), 42);
+        assert_eq!(parse_number("0x8000_0000").unwrap(), 0x8000_0000);
+        assert!(matches!(
+            parse_number("0xzz"),
+            Err(MonitorError::NotANumber)
+        ));
+        assert!(matches!(parse_number("0x_"), Err(MonitorError::NotANumber)));
+        assert!(matches!(
+            parse_number("0x1_0000_0000_0000_0000"),
+            Err(MonitorError::NotANumber)
+        ));
+    }
+
+    #[test_case]
+    fn commands_run() {
+        static ECHO: Echo = Echo;
+
+        register(&ECHO).unwrap();
+        assert!(matches!(
+            register(&ECHO),
+            Err(MonitorError::AlreadyRegistered("test-echo"))
+        ));
+        execute("  test-echo registered   commands run ").unwrap();
+
+        for line in ["", "help", "mem", "harts", "irq", "lspci", "log"] {
+            execute(line).unwrap_or_else(|err| panic!("{line:?} failed: {err}"));
+        }
+
+        assert!(matches!(
+            execute("no-such-command"),
+            Err(MonitorError::NoSuchCommand)
+        ));
+        assert!(matches!(execute("pt"), Err(MonitorError::Usage)));
+        assert!(matches!(
+            execute("test-echo 1 2 3 4 5 6 7 8"),
+            Err(MonitorError::TooManyWords)
+        ));
+    }
+
+    #[test_case]
+    fn peek_and_poke() {
+        let word = Box::new(0x1234_5678u32);
+        let paddr = vmem::virt_to_phys(&*word as *const u32 as usize);
+
+        execute(&alloc::format!("poke {paddr:#x} 0xcafe")).unwrap();
+        assert_eq!(unsafe { core::ptr::read_volatile(&*word) }, 0xcafe);
+        execute(&alloc::format!("peek {paddr:#x} 1")).unwrap();
+        execute(&alloc::format!("pt {:#x}", vmem::phys_to_virt(paddr))).unwrap();
+
+        assert!(matches!(
+            execute(&alloc::format!("peek {:#x}", paddr + 1)),
+            Err(MonitorError::Unaligned { .. })
+        ));
+    }
+}
@@ -0,0 +1,225 @@
+//! Per-hart (CPU-local) storage
+//!
+//! Variables declared with [per_cpu!](crate::per_cpu) end up in the `.percpu` section, which is
+//! only ever used as a template: every hart gets a block of its own that starts out as a copy of
+//! it, and `tp` points to the block of the hart we are running on. A variable sits at the same
+//! offset in every block as it does in the template. The boot hart gets its block from entry.s,
+//! in .bss, the other harts get one from [alloc] before they are started.
+//!
+//! Every block starts with a [Cpu], with what the kernel itself keeps per hart.
+
+use alloc::alloc::{Layout, alloc as allocate, dealloc, handle_alloc_error};
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::MAX_HARTS;
+use crate::riscv;
+use crate::symbols;
+
+/// Blocks are aligned to a cache line, so harts do not share one
+const BLOCK_ALIGN: usize = 64;
+
+/// The block of every hart, by hart id
+static BLOCKS: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+#[unsafe(link_section = ".percpu.cpu")]
+static CPU: PerCpu<Cpu> = PerCpu::new(Cpu::new());
+
+/// Declare variables that every hart has its own copy of, see [PerCpu]
+#[macro_export]
+macro_rules! per_cpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[unsafe(link_section = ".percpu")]
+            $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+        )*
+    };
+}
+
+/// A variable every hart has its own copy of. The initial value is copied bytewise into the block
+/// of every hart, so it cannot point into itself.
+///
+/// Only declare these with [per_cpu!](crate::per_cpu), they have to be in the `.percpu` section.
+/// Trap handlers run on the same hart as the code they interrupt, so anything they touch still
+/// needs atomics or a lock.
+pub struct PerCpu<T>(T);
+
+// safety: a copy is shared with the trap handlers of its hart, and with other harts through
+// [PerCpu::get_for], so T has to be Sync as well
+unsafe impl<T: Sync> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// The copy of the hart we are running on
+    pub fn get(&'static self) -> &'static T {
+        // safety: the block of this hart is a copy of the template, so the variable is at the
+        // same offset in it. Nothing runs on another hart than the one it started on
+        unsafe { &*((riscv::tp() + self.offset()) as *const T) }
+    }
+
+    fn offset(&'static self) -> usize {
+        &self.0 as *const T as usize - unsafe { symbols::PERCPU_START }
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// The copy of another hart, if it has a block yet
+    pub fn get_for(&'static self, hartid: usize) -> Option<&'static T> {
+        // safety: blocks are only freed for harts that never started, see [free]
+        block(hartid).map(|block| unsafe { &*((block + self.offset()) as *const T) })
+    }
+}
+
+/// Offsets of the fields entry.s uses, it gets them from main.rs
+pub const CPU_HARTID: usize = core::mem::offset_of!(Cpu, hartid);
+pub const CPU_STACK_TOP: usize = core::mem::offset_of!(Cpu, stack_top);
+
+// riscv::hartid loads the first word of the block
+const _: () = assert!(CPU_HARTID == 0);
+
+/// What the kernel keeps for every hart, at the start of its block
+#[repr(C)]
+pub struct Cpu {
+    pub hartid: usize,
+    /// top of the kernel stack
+    stack_top: usize,
+    /// the number of interrupts being handled, a hart is in interrupt context while non zero
+    pub irq_depth: AtomicUsize,
+    /// the thread running on this hart, 0 when there is none (the boot path and the idle loop)
+    pub current_thread: AtomicUsize,
+    pub stats: CpuStats,
+}
+
+/// Counters of everything that interrupted a hart
+#[derive(Debug, Default)]
+pub struct CpuStats {
+    pub interrupts: AtomicUsize,
+    pub exceptions: AtomicUsize,
+    pub timers: AtomicUsize,
+    pub ipis: AtomicUsize,
+}
+
+impl Cpu {
+    const fn new() -> Self {
+        Self {
+            hartid: 0,
+            stack_top: 0,
+            irq_depth: AtomicUsize::new(0),
+            current_thread: AtomicUsize::new(0),
+            stats: CpuStats {
+                interrupts: AtomicUsize::new(0),
+                exceptions: AtomicUsize::new(0),
+                timers: AtomicUsize::new(0),
+                ipis: AtomicUsize::new(0),
+            },
+        }
+    }
+}
+
+/// The [Cpu] of the hart we are running on
+pub fn cpu() -> &'static Cpu {
+    CPU.get()
+}
+
+/// The [Cpu] of another hart, if it has a block yet
+pub fn cpu_for(hartid: usize) -> Option<&'static Cpu> {
+    CPU.get_for(hartid)
+}
+
+pub fn in_interrupt() -> bool {
+    cpu().irq_depth.load(Ordering::Relaxed) != 0
+}
+
+/// The address of the block of `hartid`, if it has one
+pub fn block(hartid: usize) -> Option<usize> {
+    let block = BLOCKS.get(hartid)?.load(Ordering::Acquire);
+    (block != 0).then_some(block)
+}
+
+/// Make the block of the boot hart, set up by entry.s, reachable from other harts
+pub fn init() {
+    let hartid = riscv::hartid();
+    assert!(hartid < MAX_HARTS, "hart#{hartid} is above MAX_HARTS");
+
+    BLOCKS[hartid].store(riscv::tp(), Ordering::Release);
+}
+
+/// Set up the block of a hart that has not been started yet, and return its address. The hart is
+/// started with it in `tp`, and switches to `stack_top`
+pub fn alloc(hartid: usize, stack_top: usize) -> usize {
+    assert!(hartid < MAX_HARTS, "hart#{hartid} is above MAX_HARTS");
+
+    let start = unsafe { symbols::PERCPU_START };
+    let layout = block_layout();
+
+    // safety: the layout is not zero sized, as the template holds at least a Cpu
+    let block = unsafe { allocate(layout) };
+    if block.is_null() {
+        handle_alloc_error(layout);
+    }
+
+    // safety: the block is as large as the template, and the linker script puts the Cpu first
+    unsafe {
+        core::ptr::copy_nonoverlapping(start as *const u8, block, layout.size());
+
+        let cpu = &mut *(block as *mut Cpu);
+        cpu.hartid = hartid;
+        cpu.stack_top = stack_top;
+    }
+
+    BLOCKS[hartid].store(block as usize, Ordering::Release);
+    block as usize
+}
+
+/// Hand back the block of a hart that could not be started. The hart must never have run on it
+pub fn free(hartid: usize) {
+    assert_ne!(hartid, riscv::hartid(), "freeing the block we run on");
+
+    let block = BLOCKS[hartid].swap(0, Ordering::AcqRel);
+    if block != 0 {
+        // safety: the block came from [alloc], and nobody runs on it
+        unsafe { dealloc(block as *mut u8, block_layout()) };
+    }
+}
+
+fn block_layout() -> Layout {
+    let (start, end) = unsafe { (symbols::PERCPU_START, symbols::PERCPU_END) };
+    Layout::from_size_align(end - start, BLOCK_ALIGN).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::per_cpu! {
+        static COUNTER: AtomicUsize = AtomicUsize::new(7);
+    }
+
+    #[test_case]
+    fn per_cpu_variables() {
+        // the template keeps its initial value, we only ever touch our own copy
+        COUNTER.get().fetch_add(1, Ordering::Relaxed);
+        assert_eq!(COUNTER.0.load(Ordering::Relaxed), 7);
+        assert_eq!(COUNTER.get().load(Ordering::Relaxed), 8);
+
+        let mine = COUNTER.get_for(riscv::hartid()).unwrap();
+        assert!(core::ptr::eq(mine, COUNTER.get()));
+    }
+
+    #[test_case]
+    fn cpu_block() {
+        assert_eq!(cpu().hartid, riscv::hartid());
+        assert!(!in_interrupt());
+
+        let exceptions = cpu().stats.exceptions.load(Ordering::Relaxed);
+        riscv::ebreak();
+        assert_eq!(
+            cpu().stats.exceptions.load(Ordering::Relaxed),
+            exceptions + 1
+        );
+    }
+}
@@ -0,0 +1,191 @@
+//! Discovery of physical memory
+//!
+//! The amount of RAM we get is decided by whoever boots us, so we read it from the `/memory` nodes
+//! of the FDT. Everything that is already in use (the kernel image, the FDT blob itself and all of
+//! `/reserved-memory`) is cut out, and what remains is handed to the frame allocator.
+
+use spin::Once;
+
+use crate::vmem::virt_to_phys;
+use crate::{PAGE_SIZE, allocator, round_down_by, round_up_by, symbols};
+
+/// Maximum number of disjoint regions we keep track of
+const MAX_REGIONS: usize = 32;
+
+static MEMORY: Once<PhysMemory> = Once::new();
+
+/// A range of physical memory, `end` is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Region {
+    pub const fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub const fn pages(&self) -> usize {
+        self.size() / PAGE_SIZE
+    }
+}
+
+/// A fixed size list of regions, we cannot use a `Vec` as we need this before the heap exists
+#[derive(Debug, Clone, Copy)]
+pub struct RegionList {
+    regions: [Region; MAX_REGIONS],
+    len: usize,
+}
+
+impl RegionList {
+    pub const fn new() -> Self {
+        Self {
+            regions: [Region { start: 0, end: 0 }; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Add a region to the list. Empty regions are ignored
+    pub fn push(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+
+        assert!(self.len < MAX_REGIONS, "too many memory regions");
+        self.regions[self.len] = Region { start, end };
+        self.len += 1;
+    }
+
+    /// Cut the range between `start` and `end` out of every region in the list
+    pub fn subtract(&mut self, start: usize, end: usize) {
+        let old = *self;
+        self.len = 0;
+
+        for region in old.iter() {
+            if end <= region.start || start >= region.end {
+                self.push(region.start, region.end);
+                continue;
+            }
+
+            // the part on the left of the hole, and the part on the right of it
+            self.push(region.start, start.max(region.start).min(region.end));
+            self.push(end.min(region.end).max(region.start), region.end);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions[..self.len].iter()
+    }
+
+    /// Total number of bytes in all regions
+    pub fn size(&self) -> usize {
+        self.iter().map(Region::size).sum()
+    }
+}
+
+#[derive(Debug)]
+pub struct PhysMemory {
+    /// all of the RAM, as described by the FDT
+    pub ram: RegionList,
+    /// RAM that was handed over to the frame allocator
+    pub free: RegionList,
+}
+
+/// Find out how much memory we have and hand everything that is unused to the frame allocator.
+/// `fdt_ptr` is the physical address of the fdt
+pub fn init(fdt: fdt::Fdt, fdt_ptr: usize) -> &'static PhysMemory {
+    let mut ram = RegionList::new();
+
+    for region in fdt.memory().regions() {
+        let Some(size) = region.size else { continue };
+        let start = region.starting_address as usize;
+
+        ram.push(
+            round_up_by(start, PAGE_SIZE),
+            round_down_by(start + size, PAGE_SIZE),
+        );
+    }
+
+    let mut free = ram;
+
+    // the kernel image, along with the boot stack
+    let (kernel_start, kernel_end) = unsafe { (symbols::MEMTOP, symbols::KERNEL_END) };
+    let kernel_size = kernel_end - kernel_start;
+    reserve(&mut free, virt_to_phys(kernel_start), kernel_size, "kernel");
+
+    // we keep using the fdt after this, so it better not be overwritten
+    reserve(&mut free, fdt_ptr, fdt.total_size(), "fdt");
+
+    for reservation in fdt.memory_reservations() {
+        let addr = reservation.address() as usize;
+        reserve(&mut free, addr, reservation.size(), "memreserve");
+    }
+
+    // this includes the memory that OpenSBI protects with PMP, touching it is an access fault
+    let reserved_nodes = fdt.find_node("/reserved-memory").into_iter();
+    for node in reserved_nodes.flat_map(|node| node.children()) {
+        for region in node.reg().into_iter().flatten() {
+            let addr = region.starting_address as usize;
+            reserve(&mut free, addr, region.size.unwrap_or(0), node.name);
+        }
+    }
+
+    // safety: the free regions are RAM that nothing else is using
+    unsafe { allocator::init_frames(&mut free) }.expect("could not init frame allocator");
+
+    let memory = MEMORY.call_once(|| PhysMemory { ram, free });
+
+    log::info!(
+        "[PMEM] {} MiB of RAM, {} MiB handed to the frame allocator",
+        memory.ram.size() / (1024 * 1024),
+        memory.free.size() / (1024 * 1024)
+    );
+
+    memory
+}
+
+fn reserve(list: &mut RegionList, addr: usize, size: usize, name: &str) {
+    let start = round_down_by(addr, PAGE_SIZE);
+    let end = round_up_by(addr + size, PAGE_SIZE);
+
+    log::debug!("[PMEM] reserved {start:#x}..{end:#x} ({name})");
+    list.subtract(start, end);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regions(list: &RegionList) -> alloc::vec::Vec<(usize, usize)> {
+        list.iter().map(|r| (r.start, r.end)).collect()
+    }
+
+    #[test_case]
+    fn subtract() {
+        let mut list = RegionList::new();
+        list.push(0x1000, 0x9000);
+        list.push(0x10000, 0x20000);
+        list.push(0x5000, 0x5000);
+        assert_eq!(regions(&list), [(0x1000, 0x9000), (0x10000, 0x20000)]);
+
+        // a hole in the middle splits the region in two
+        list.subtract(0x3000, 0x4000);
+        assert_eq!(
+            regions(&list),
+            [(0x1000, 0x3000), (0x4000, 0x9000), (0x10000, 0x20000)]
+        );
+
+        // overlapping the edges only trims the regions
+        list.subtract(0x8000, 0x11000);
+        assert_eq!(
+            regions(&list),
+            [(0x1000, 0x3000), (0x4000, 0x8000), (0x11000, 0x20000)]
+        );
+
+        // covering a region completely removes it
+        list.subtract(0, 0x3000);
+        assert_eq!(regions(&list), [(0x4000, 0x8000), (0x11000, 0x20000)]);
+        assert_eq!(list.size(), 0x4000 + 0xf000);
+    }
+}
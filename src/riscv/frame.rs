@@ -1,40 +1,59 @@
+/// Size of the frame on the stack, rounded up to keep the stack 16 byte aligned. kernelvec.s gets
+/// it from main.rs
+pub const FRAME_SIZE: usize = 8 * 36;
+
+const _: () = assert!(core::mem::size_of::<Frame>() <= FRAME_SIZE && FRAME_SIZE.is_multiple_of(16));
+
+/// Everything a trap saves. Changes to the general purpose registers, `sepc` and `sstatus` are
+/// restored on return from the trap, so a handler can resume at a different instruction, or in a
+/// different context altogether
 #[repr(C)]
 #[derive(Debug)]
 pub struct Frame {
-    ra: usize,
-    sp: usize,
-    gp: usize,
-    tp: usize,
-    t0: usize,
-    t1: usize,
-    t2: usize,
-    fp: usize,
-    s1: usize,
-    a0: usize,
-    a1: usize,
-    a2: usize,
-    a3: usize,
-    a4: usize,
-    a5: usize,
-    a6: usize,
-    a7: usize,
-    s2: usize,
-    s3: usize,
-    s4: usize,
-    s5: usize,
-    s6: usize,
-    s7: usize,
-    s8: usize,
-    s9: usize,
-    s10: usize,
-    s11: usize,
-    t3: usize,
-    t4: usize,
-    t5: usize,
-    t6: usize,
+    pub ra: usize,
+    pub sp: usize,
+    pub gp: usize,
+    pub tp: usize,
+    pub t0: usize,
+    pub t1: usize,
+    pub t2: usize,
+    pub fp: usize,
+    pub s1: usize,
+    pub a0: usize,
+    pub a1: usize,
+    pub a2: usize,
+    pub a3: usize,
+    pub a4: usize,
+    pub a5: usize,
+    pub a6: usize,
+    pub a7: usize,
+    pub s2: usize,
+    pub s3: usize,
+    pub s4: usize,
+    pub s5: usize,
+    pub s6: usize,
+    pub s7: usize,
+    pub s8: usize,
+    pub s9: usize,
+    pub s10: usize,
+    pub s11: usize,
+    pub t3: usize,
+    pub t4: usize,
+    pub t5: usize,
+    pub t6: usize,
+    /// the instruction that trapped, or the one to continue at for interrupts
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub scause: usize,
+    pub stval: usize,
 }
 
 impl Frame {
+    /// The stack pointer at the time of the trap
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
     pub fn pretty_print(&self) {
         const RESET: &str = crate::writer::RESET;
 
@@ -79,6 +98,10 @@ impl Frame {
             ("gp", self.gp),
             ("tp", self.tp),
             ("fp", self.fp),
+            ("sepc", self.sepc),
+            ("status", self.sstatus),
+            ("cause", self.scause),
+            ("tval", self.stval),
         ];
 
         let max_rows = column_1
@@ -24,6 +24,20 @@ pub fn pause() {
     }
 }
 
+/// The frame pointer of the function this is inlined into
+#[inline(always)]
+pub fn fp() -> usize {
+    let fp: usize;
+    unsafe { asm!("mv {}, s0", out(reg) fp, options(nomem, nostack)) };
+    fp
+}
+
+/// `EBREAK` instruction wrapper, traps into the kernel which skips it
+#[inline]
+pub fn ebreak() {
+    unsafe { asm!("ebreak", options(nomem, nostack)) };
+}
+
 #[inline]
 pub fn pauseloop() -> ! {
     loop {
@@ -47,6 +61,35 @@ pub fn wfiloop() {
     }
 }
 
+/// The id of the hart we are running on, the first word of its per-CPU block
+#[inline]
+pub fn hartid() -> usize {
+    unsafe {
+        let hartid: usize;
+        asm!("ld {}, 0(tp)", out(reg) hartid, options(readonly, nostack));
+        hartid
+    }
+}
+
+/// The per-CPU block of the hart we are running on, see [crate::percpu]
+#[inline]
+pub fn tp() -> usize {
+    unsafe {
+        let tp: usize;
+        asm!("mv {}, tp", out(reg) tp, options(nomem, nostack));
+        tp
+    }
+}
+
+/// `RDCYCLE` instruction wrapper, the number of cycles this hart has executed
+pub fn cycle() -> usize {
+    unsafe {
+        let cycle: usize;
+        asm!("rdcycle {}", out(reg) cycle, options(nomem, nostack));
+        cycle
+    }
+}
+
 /// `TIME` instruction wrapper
 pub fn time() -> usize {
     unsafe {
@@ -62,6 +105,11 @@ pub fn sfence_vma() {
     unsafe { asm!("sfence.vma zero, zero", options(nomem, nostack)) };
 }
 
+// flush the TLB entries for a single virtual address, in all address spaces
+pub fn sfence_vma_addr(vaddr: usize) {
+    unsafe { asm!("sfence.vma {}, zero", in(reg) vaddr, options(nomem, nostack)) };
+}
+
 pub mod satp {
     use super::*;
 
@@ -78,6 +126,84 @@ pub mod satp {
     }
 }
 
+pub mod sepc {
+    use super::*;
+
+    pub fn write(value: usize) {
+        unsafe { asm!("csrw sepc, {}", in(reg) value, options(nomem, nostack)) };
+    }
+
+    pub fn read() -> usize {
+        unsafe {
+            let sepc: usize;
+            asm!("csrr {}, sepc", out(reg) sepc, options(nomem, nostack));
+            sepc
+        }
+    }
+}
+
+pub mod sip {
+    use super::*;
+
+    /// Acknowledge a supervisor software interrupt (an IPI)
+    pub fn clear_soft() {
+        unsafe { asm!("csrc sip, {}", in(reg) 1 << 1, options(nomem, nostack)) };
+    }
+}
+
+/// The S-level interrupt file of the hart's IMSIC (Ssaia). Its registers are reached indirectly,
+/// by selecting one in `siselect` and accessing it through `sireg`
+pub mod imsic {
+    use super::*;
+
+    pub fn read(reg: usize) -> usize {
+        interrupt::free(|| unsafe {
+            let value: usize;
+            // siselect, sireg
+            asm!("csrw 0x150, {}", "csrr {}, 0x151", in(reg) reg, out(reg) value, options(nomem, nostack));
+            value
+        })
+    }
+
+    pub fn write(reg: usize, value: usize) {
+        interrupt::free(|| unsafe {
+            asm!("csrw 0x150, {}", "csrw 0x151, {}", in(reg) reg, in(reg) value, options(nomem, nostack));
+        })
+    }
+
+    /// Claim the highest priority pending identity, 0 if there is none
+    pub fn claim() -> u32 {
+        unsafe {
+            let topei: usize;
+            // stopei, writing it claims the identity that was read
+            asm!("csrrw {}, 0x15c, zero", out(reg) topei, options(nomem, nostack));
+            (topei >> 16) as u32
+        }
+    }
+}
+
+/// Supervisor timer compare register of the Sstc extension. The timer interrupt is pending while
+/// `time` is at or past it
+pub mod stimecmp {
+    use super::*;
+
+    pub fn write(value: usize) {
+        unsafe { asm!("csrw 0x14d, {}", in(reg) value, options(nomem, nostack)) };
+    }
+}
+
+pub mod stval {
+    use super::*;
+
+    pub fn read() -> usize {
+        unsafe {
+            let stval: usize;
+            asm!("csrr {}, stval", out(reg) stval, options(nomem, nostack));
+            stval
+        }
+    }
+}
+
 pub mod interrupt {
     use super::*;
 
@@ -124,6 +250,12 @@ pub mod interrupt {
             scause
         };
 
+        decode(scause)
+    }
+
+    /// Make sense of the value of `scause`
+    #[inline]
+    pub fn decode(scause: usize) -> Trap {
         let int = ((scause >> 63) & 1) == 1;
         let cause = scause & ((1 << 63) - 1);
 
@@ -141,7 +273,37 @@ pub mod interrupt {
     /// Do not call this function inside a critical section.
     #[inline]
     pub unsafe fn enable_all() {
-        unsafe { asm!("csrw sie, {}", in(reg) 1 << 5 | 1 << 11 | 1 << 9, options(nomem, nostack)) };
+        unsafe {
+            asm!("csrw sie, {}", in(reg) 1 << 1 | 1 << 5 | 1 << 11 | 1 << 9, options(nomem, nostack))
+        };
+    }
+
+    const SIE: usize = 1 << 1;
+
+    /// Stop the current hart from taking interrupts, and return whether it was taking them before
+    #[inline]
+    pub fn disable_local() -> bool {
+        let sstatus: usize;
+        unsafe { asm!("csrrc {}, sstatus, {}", out(reg) sstatus, in(reg) SIE, options(nostack)) };
+        sstatus & SIE != 0
+    }
+
+    /// Undo a [disable_local], `enabled` is what it returned
+    #[inline]
+    pub fn restore_local(enabled: bool) {
+        if enabled {
+            unsafe { asm!("csrs sstatus, {}", in(reg) SIE, options(nostack)) };
+        }
+    }
+
+    /// Run `f` with interrupts disabled on the current hart. Locks that are also taken in trap
+    /// context have to be taken like this, or an interrupt could deadlock on them
+    #[inline]
+    pub fn free<R>(f: impl FnOnce() -> R) -> R {
+        let enabled = disable_local();
+        let result = f();
+        restore_local(enabled);
+        result
     }
 
     /// Disables all interrupts in the current hart (supervisor mode).
@@ -158,4 +320,8 @@ macro_rules! include_asm {
     ($file:expr $(,)?) => {
         core::arch::global_asm!(include_str!($file));
     };
+    // constants the assembly takes from Rust, as `name = const value`
+    ($file:expr, $($operands:tt)+) => {
+        core::arch::global_asm!(include_str!($file), $($operands)+);
+    };
 }
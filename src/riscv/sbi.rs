@@ -1,3 +1,10 @@
+//! Calls into the Supervisor Binary Interface, implemented by the firmware below us (OpenSBI)
+//!
+//! Every extension other than the base one is optional. Calling one that is not there returns
+//! [SbiError::NotSupported], [init] finds out which ones we have up front.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
 #[derive(Debug, Default)]
 struct Args {
     a0: usize,
@@ -34,6 +41,294 @@ fn ecall(mut args: Args, fid: usize, eid: usize) -> EcallRet {
     EcallRet { a, b }
 }
 
+/// A set of harts, as the SBI takes them: bit `n` of `mask` stands for hart `base + n`
+#[derive(Debug, Clone, Copy)]
+pub struct HartMask {
+    pub mask: usize,
+    pub base: usize,
+}
+
+impl HartMask {
+    pub const fn from_mask(mask: usize) -> Self {
+        Self { mask, base: 0 }
+    }
+}
+
+/// The standard SBI error codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum SbiError {
+    #[error("failed")]
+    Failed,
+    #[error("not supported")]
+    NotSupported,
+    #[error("invalid parameter")]
+    InvalidParam,
+    #[error("denied")]
+    Denied,
+    #[error("invalid address")]
+    InvalidAddress,
+    #[error("already available")]
+    AlreadyAvailable,
+    #[error("already started")]
+    AlreadyStarted,
+    #[error("already stopped")]
+    AlreadyStopped,
+    #[error("shared memory not available")]
+    NoShmem,
+    #[error("invalid state")]
+    InvalidState,
+    #[error("bad range")]
+    BadRange,
+    #[error("timed out")]
+    Timeout,
+    #[error("input/output error")]
+    Io,
+    #[error("unknown error {0}")]
+    Unknown(isize),
+}
+
+impl SbiError {
+    fn from_code(code: isize) -> Self {
+        match code {
+            -1 => Self::Failed,
+            -2 => Self::NotSupported,
+            -3 => Self::InvalidParam,
+            -4 => Self::Denied,
+            -5 => Self::InvalidAddress,
+            -6 => Self::AlreadyAvailable,
+            -7 => Self::AlreadyStarted,
+            -8 => Self::AlreadyStopped,
+            -9 => Self::NoShmem,
+            -10 => Self::InvalidState,
+            -11 => Self::BadRange,
+            -12 => Self::Timeout,
+            -13 => Self::Io,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+/// Turn the error code in a0 into a result, the value is in a1
+fn check(ret: EcallRet) -> Result<usize, SbiError> {
+    match ret.a as isize {
+        0 => Ok(ret.b),
+        code => Err(SbiError::from_code(code)),
+    }
+}
+
+/// The extensions we know of, by extension id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Extension {
+    Base = 0x10,
+    Time = 0x54494D45,
+    Ipi = 0x735049,
+    Rfence = 0x52464E43,
+    Hsm = 0x48534D,
+    Srst = 0x53525354,
+    Pmu = 0x504D55,
+    Dbcn = 0x4442434E,
+    Susp = 0x53555350,
+    Cppc = 0x43505043,
+}
+
+impl Extension {
+    pub const ALL: [Extension; 10] = [
+        Self::Base,
+        Self::Time,
+        Self::Ipi,
+        Self::Rfence,
+        Self::Hsm,
+        Self::Srst,
+        Self::Pmu,
+        Self::Dbcn,
+        Self::Susp,
+        Self::Cppc,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Base => "base",
+            Self::Time => "time",
+            Self::Ipi => "ipi",
+            Self::Rfence => "rfence",
+            Self::Hsm => "hsm",
+            Self::Srst => "srst",
+            Self::Pmu => "pmu",
+            Self::Dbcn => "dbcn",
+            Self::Susp => "susp",
+            Self::Cppc => "cppc",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        let index = Self::ALL.iter().position(|&ext| ext == self).unwrap();
+        1 << index
+    }
+}
+
+/// Bit `n` is set if [Extension::ALL]\[n\] is available
+static EXTENSIONS: AtomicU32 = AtomicU32::new(0);
+
+/// Probe for every extension we know of, and print what the firmware has to offer
+pub fn init() {
+    let extensions = Extension::ALL
+        .iter()
+        .filter(|&&ext| base::probe_extension(ext as usize).unwrap_or(false))
+        .fold(0, |mask, &ext| mask | ext.bit());
+    EXTENSIONS.store(extensions, Ordering::Relaxed);
+    // everything from here on goes to the console the firmware actually has
+    crate::writer::select_console();
+
+    let version = base::get_spec_version().unwrap_or(0);
+    let (major, minor) = (version >> 24 & 0x7f, version & 0xff_ffff);
+    let implementation = base::get_impl_id().map_or("unknown", base::impl_name);
+    let impl_version = base::get_impl_version().unwrap_or(0);
+
+    log::info!(
+        "[SBI] {implementation} {:#x}, implementing SBI v{major}.{minor}",
+        impl_version
+    );
+    log::info!(
+        "[SBI] mvendorid {:#x}, marchid {:#x}, mimpid {:#x}",
+        base::get_mvendorid().unwrap_or(0),
+        base::get_marchid().unwrap_or(0),
+        base::get_mimpid().unwrap_or(0),
+    );
+
+    log::info!("[SBI] extensions:{}", Available);
+}
+
+/// Lists every extension the firmware has, we cannot allocate this early on
+struct Available;
+
+impl core::fmt::Display for Available {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for ext in Extension::ALL.iter().filter(|&&ext| has_extension(ext)) {
+            write!(f, " {}", ext.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the firmware has `ext`, as found by [init]
+pub fn has_extension(ext: Extension) -> bool {
+    EXTENSIONS.load(Ordering::Relaxed) & ext.bit() != 0
+}
+
+pub mod base {
+    //! Base Extension (EID #0x10)
+    //!
+    //! The only extension every SBI implementation has, as of SBI v0.2
+
+    use super::*;
+    const EID: usize = Extension::Base as usize;
+    const FID_GET_SPEC_VERSION: usize = 0;
+    const FID_GET_IMPL_ID: usize = 1;
+    const FID_GET_IMPL_VERSION: usize = 2;
+    const FID_PROBE_EXTENSION: usize = 3;
+    const FID_GET_MVENDORID: usize = 4;
+    const FID_GET_MARCHID: usize = 5;
+    const FID_GET_MIMPID: usize = 6;
+
+    fn call(fid: usize, a0: usize) -> Result<usize, SbiError> {
+        let args = Args {
+            a0,
+            ..Default::default()
+        };
+
+        check(ecall(args, fid, EID))
+    }
+
+    /// The major version in bits 24 to 30, the minor one in bits 0 to 23
+    pub fn get_spec_version() -> Result<usize, SbiError> {
+        call(FID_GET_SPEC_VERSION, 0)
+    }
+
+    pub fn get_impl_id() -> Result<usize, SbiError> {
+        call(FID_GET_IMPL_ID, 0)
+    }
+
+    /// Encoded in a way specific to the implementation
+    pub fn get_impl_version() -> Result<usize, SbiError> {
+        call(FID_GET_IMPL_VERSION, 0)
+    }
+
+    pub fn probe_extension(eid: usize) -> Result<bool, SbiError> {
+        call(FID_PROBE_EXTENSION, eid).map(|available| available != 0)
+    }
+
+    pub fn get_mvendorid() -> Result<usize, SbiError> {
+        call(FID_GET_MVENDORID, 0)
+    }
+
+    pub fn get_marchid() -> Result<usize, SbiError> {
+        call(FID_GET_MARCHID, 0)
+    }
+
+    pub fn get_mimpid() -> Result<usize, SbiError> {
+        call(FID_GET_MIMPID, 0)
+    }
+
+    pub fn impl_name(id: usize) -> &'static str {
+        match id {
+            0 => "Berkeley Boot Loader",
+            1 => "OpenSBI",
+            2 => "Xvisor",
+            3 => "KVM",
+            4 => "RustSBI",
+            5 => "Diosix",
+            6 => "Coffer",
+            7 => "Xen Project",
+            8 => "PolarFire Hart Software Services",
+            9 => "coreboot",
+            10 => "oreboot",
+            11 => "bhyve",
+            _ => "unknown",
+        }
+    }
+}
+
+pub mod legacy {
+    //! The legacy extensions of SBI v0.1, which we only fall back to. They return nothing we can
+    //! check
+
+    use super::*;
+    const EID_SET_TIMER: usize = 0x00;
+    const EID_CONSOLE_PUTCHAR: usize = 0x01;
+    const EID_CONSOLE_GETCHAR: usize = 0x02;
+    const EID_SHUTDOWN: usize = 0x08;
+
+    pub fn set_timer(stime: usize) {
+        let args = Args {
+            a0: stime,
+            ..Default::default()
+        };
+
+        ecall(args, 0, EID_SET_TIMER);
+    }
+
+    pub fn console_putchar(byte: u8) {
+        let args = Args {
+            a0: byte as usize,
+            ..Default::default()
+        };
+
+        ecall(args, 0, EID_CONSOLE_PUTCHAR);
+    }
+
+    /// The next byte typed into the firmware console, if there is one
+    pub fn console_getchar() -> Option<u8> {
+        // this returns -1 in a0 when there is nothing, instead of an error code
+        let byte = ecall(Args::default(), 0, EID_CONSOLE_GETCHAR).a;
+        u8::try_from(byte).ok()
+    }
+
+    pub fn shutdown() {
+        ecall(Args::default(), 0, EID_SHUTDOWN);
+    }
+}
+
 pub mod hsm {
     //! # Hart State Management Extension (EID: 0x48534D "HSM")
     //!
@@ -44,57 +339,217 @@ pub mod hsm {
     //! |----------|----------------|-------------|
     //! | 0        | STARTED        | The hart is physically powered up and executing normally |
     //! | 1        | STOPPED        | The hart is not executing in supervisor-mode or any lower privilege mode. It is probably powered-down by the SBI implementation if the underlying platform has a mechanism to physically power-down harts. |
-    //! | 3        | START_PENDING  | Some other hart has requested to start (or power-up) the hart from the STOPPED state and the SBI implementation is still working to get the hart in the STARTED state. |
-    //! | 4        | STOP_PENDING   | The hart has requested to stop (or power-down) itself from the STARTED state and the SBI implementation is still working to get the hart in the STOPPED state. |
-    //! | 5        | SUSPENDED      | This hart is in a platform specific suspend (or low power) state. |
+    //! | 2        | START_PENDING  | Some other hart has requested to start (or power-up) the hart from the STOPPED state and the SBI implementation is still working to get the hart in the STARTED state. |
+    //! | 3        | STOP_PENDING   | The hart has requested to stop (or power-down) itself from the STARTED state and the SBI implementation is still working to get the hart in the STOPPED state. |
+    //! | 4        | SUSPENDED      | This hart is in a platform specific suspend (or low power) state. |
+    //! | 5        | SUSPEND_PENDING| The hart has requested to put itself in a platform specific low power state from the STARTED state and the SBI implementation is still working to get the hart in the SUSPENDED state. |
     //! | 6        | RESUME_PENDING | An interrupt or platform specific hardware event has caused the hart to resume normal execution from the SUSPENDED state and the SBI implementation is still working to get the hart in the STARTED state. |
 
     use super::*;
 
-    const EID: usize = 0x48534D;
+    const EID: usize = Extension::Hsm as usize;
     const FID_HART_START: usize = 0;
+    const FID_HART_STOP: usize = 1;
+    const FID_HART_GET_STATUS: usize = 2;
+    const FID_HART_SUSPEND: usize = 3;
+
+    /// The default retentive suspend: the hart continues right after the call, like after `wfi`
+    pub const SUSPEND_RETENTIVE: u32 = 0x0000_0000;
+    /// The default non-retentive suspend: the hart resumes at the given address, as if started
+    #[allow(unused)]
+    pub const SUSPEND_NON_RETENTIVE: u32 = 0x8000_0000;
 
-    pub fn start(hartid: usize, ra: usize, a2: usize) {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum HartState {
+        Started = 0,
+        Stopped = 1,
+        StartPending = 2,
+        StopPending = 3,
+        Suspended = 4,
+        SuspendPending = 5,
+        ResumePending = 6,
+    }
+
+    /// Start `hartid` in supervisor mode at the physical address `start`, with paging disabled. It
+    /// gets its id in a0 and `opaque` in a1
+    pub fn start(hartid: usize, start: usize, opaque: usize) -> Result<(), SbiError> {
         let args = Args {
             a0: hartid,
-            a1: ra,
-            a2,
+            a1: start,
+            a2: opaque,
             ..Default::default()
         };
 
-        let ret = ecall(args, FID_HART_START, EID);
-        //crate::println!("{ret:?}");
+        check(ecall(args, FID_HART_START, EID)).map(|_| ())
+    }
+
+    /// Hand the current hart back to the SBI implementation. Only returns if that failed
+    pub fn stop() -> SbiError {
+        let ret = ecall(Args::default(), FID_HART_STOP, EID);
+        SbiError::from_code(ret.a as isize)
+    }
+
+    pub fn get_status(hartid: usize) -> Result<HartState, SbiError> {
+        let args = Args {
+            a0: hartid,
+            ..Default::default()
+        };
+
+        let state = match check(ecall(args, FID_HART_GET_STATUS, EID))? {
+            0 => HartState::Started,
+            1 => HartState::Stopped,
+            2 => HartState::StartPending,
+            3 => HartState::StopPending,
+            4 => HartState::Suspended,
+            5 => HartState::SuspendPending,
+            6 => HartState::ResumePending,
+            // the implementation made up a state
+            _ => return Err(SbiError::Failed),
+        };
+
+        Ok(state)
+    }
+
+    /// Put the current hart in a low power state until an interrupt comes in. A retentive suspend
+    /// returns, a non-retentive one resumes at `resume` like [start] would
+    pub fn suspend(suspend_type: u32, resume: usize, opaque: usize) -> Result<(), SbiError> {
+        let args = Args {
+            a0: suspend_type as usize,
+            a1: resume,
+            a2: opaque,
+            ..Default::default()
+        };
+
+        check(ecall(args, FID_HART_SUSPEND, EID)).map(|_| ())
     }
 }
 
 pub mod time {
     use super::*;
-    const EID: usize = 0x54494D45;
+    const EID: usize = Extension::Time as usize;
     const FID_SET_TIMER: usize = 0;
 
-    pub fn set_timer(cycles: usize) {
+    /// Have the timer interrupt go off once `time` reaches `stime`, this also clears it if it is
+    /// pending. Falls back to the legacy call on firmware without the TIME extension
+    pub fn set_timer(stime: usize) -> Result<(), SbiError> {
         let args = Args {
-            a0: cycles,
+            a0: stime,
             ..Default::default()
         };
 
-        ecall(args, FID_SET_TIMER, EID);
+        match check(ecall(args, FID_SET_TIMER, EID)) {
+            Err(SbiError::NotSupported) => {
+                legacy::set_timer(stime);
+                Ok(())
+            }
+            ret => ret.map(|_| ()),
+        }
+    }
+}
+
+pub mod ipi {
+    //! IPI Extension (EID #0x735049 "sPI: s-mode IPI")
+
+    use super::*;
+    const EID: usize = Extension::Ipi as usize;
+    const FID_SEND_IPI: usize = 0;
+
+    /// Raise a supervisor software interrupt on every hart in `harts`
+    pub fn send_ipi(harts: HartMask) -> Result<(), SbiError> {
+        let args = Args {
+            a0: harts.mask,
+            a1: harts.base,
+            ..Default::default()
+        };
+
+        check(ecall(args, FID_SEND_IPI, EID)).map(|_| ())
+    }
+}
+
+pub mod rfence {
+    //! RFENCE Extension (EID #0x52464E43 "RFNC")
+    //!
+    //! The calls return once every hart in the set has executed the fence
+
+    use super::*;
+    const EID: usize = Extension::Rfence as usize;
+    const FID_REMOTE_FENCE_I: usize = 0;
+    const FID_REMOTE_SFENCE_VMA: usize = 1;
+
+    /// Execute `fence.i` on every hart in `harts`
+    pub fn remote_fence_i(harts: HartMask) -> Result<(), SbiError> {
+        let args = Args {
+            a0: harts.mask,
+            a1: harts.base,
+            ..Default::default()
+        };
+
+        check(ecall(args, FID_REMOTE_FENCE_I, EID)).map(|_| ())
+    }
+
+    /// Execute `sfence.vma` for `size` bytes starting at `start` on every hart in `harts`
+    pub fn remote_sfence_vma(harts: HartMask, start: usize, size: usize) -> Result<(), SbiError> {
+        let args = Args {
+            a0: harts.mask,
+            a1: harts.base,
+            a2: start,
+            a3: size,
+            ..Default::default()
+        };
+
+        check(ecall(args, FID_REMOTE_SFENCE_VMA, EID)).map(|_| ())
     }
 }
 
 pub mod dbcn {
+    //! Debug Console Extension (EID #0x4442434E "DBCN")
+
     use super::*;
-    const EID: usize = 0x4442434E;
+    const EID: usize = Extension::Dbcn as usize;
     const FID_WRITE: usize = 0;
+    const FID_READ: usize = 1;
+
+    pub fn write(string: &str) -> Result<(), SbiError> {
+        let mut bytes = string.as_bytes();
+
+        // the SBI wants a physical address, and pages that are next to each other in virtual memory
+        // do not have to be in physical memory. So we never write across a page boundary
+        while !bytes.is_empty() {
+            let addr = bytes.as_ptr() as usize;
+            let len = bytes.len().min(crate::PAGE_SIZE - addr % crate::PAGE_SIZE);
+
+            // the console could be used to report that an address is not mapped, so we do not
+            // panic on it here
+            let paddr = crate::vmem::try_virt_to_phys(addr).ok_or(SbiError::InvalidAddress)?;
+            let args = Args {
+                a0: len,
+                a1: paddr,
+                ..Default::default()
+            };
+
+            // the console can take less than we gave it
+            let written = check(ecall(args, FID_WRITE, EID))?;
+            bytes = &bytes[written.min(len)..];
+        }
+
+        Ok(())
+    }
+
+    /// Read whatever is waiting in the console into `buf`, without waiting for more. Returns the
+    /// number of bytes read
+    pub fn read(buf: &mut [u8]) -> Result<usize, SbiError> {
+        // same as write, the buffer has to be physically contiguous
+        let addr = buf.as_mut_ptr() as usize;
+        let len = buf.len().min(crate::PAGE_SIZE - addr % crate::PAGE_SIZE);
 
-    pub fn write(string: &str) {
+        let paddr = crate::vmem::try_virt_to_phys(addr).ok_or(SbiError::InvalidAddress)?;
         let args = Args {
-            a0: string.len(),
-            a1: string.as_ptr() as usize,
+            a0: len,
+            a1: paddr,
             ..Default::default()
         };
 
-        ecall(args, FID_WRITE, EID);
+        check(ecall(args, FID_READ, EID)).map(|read| read.min(len))
     }
 }
 
@@ -102,7 +557,7 @@ pub mod srst {
     //! System Reset Extension (EID #0x53525354 "SRST")
 
     use super::*;
-    const EID: usize = 0x53525354;
+    const EID: usize = Extension::Srst as usize;
     const FID_SYSTEM_RESET: usize = 0;
 
     #[repr(u32)]
@@ -118,13 +573,43 @@ pub mod srst {
         Failure = 1,
     }
 
-    pub fn system_reset(reset_type: ResetType, reason: ResetReason) {
+    /// Only returns if the reset failed. Firmware without the SRST extension can still shut
+    /// down through the legacy call
+    pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> SbiError {
+        let shutdown = matches!(reset_type, ResetType::Shutdown);
         let args = Args {
             a0: reset_type as usize,
             a1: reason as usize,
             ..Default::default()
         };
 
-        ecall(args, FID_SYSTEM_RESET, EID);
+        let error = SbiError::from_code(ecall(args, FID_SYSTEM_RESET, EID).a as isize);
+        if error == SbiError::NotSupported && shutdown {
+            legacy::shutdown();
+        }
+
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn base_extension() {
+        // probing needs at least SBI v0.2
+        let version = base::get_spec_version().unwrap();
+        assert!(version >> 24 > 0 || version & 0xff_ffff >= 2);
+
+        assert!(has_extension(Extension::Base));
+        assert_eq!(base::probe_extension(0x0bad_0bad), Ok(false));
+    }
+
+    #[test_case]
+    fn error_codes() {
+        assert_eq!(SbiError::from_code(-2), SbiError::NotSupported);
+        assert_eq!(SbiError::from_code(-13), SbiError::Io);
+        assert_eq!(SbiError::from_code(-42), SbiError::Unknown(-42));
     }
 }
@@ -0,0 +1,283 @@
+//! Bringing harts up and down, and running code on other harts
+//!
+//! Harts are started through the SBI hart state management extension, at `_start_hart` with their
+//! per-CPU block. A hart can [stop] itself to be parked by the SBI implementation, and be brought
+//! back with [restart], in which case it starts over at [kinit](crate::kinit::kinit).
+//!
+//! Every hart has a mailbox of calls. [call_on] posts a call to the mailbox of every target, sends
+//! them an IPI, and waits until all of them ran it. Harts take the calls in trap context, when the
+//! supervisor software interrupt comes in. After a fatal error, [halt_others] makes them stop on
+//! that interrupt instead.
+
+use alloc::collections::VecDeque;
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::MAX_HARTS;
+use crate::percpu;
+use crate::riscv::sbi::SbiError;
+use crate::riscv::sbi::hsm::{self, HartState};
+use crate::riscv::{self, sbi};
+use crate::time::{Duration, Instant};
+use crate::vmem;
+
+/// How long a hart gets to go from START_PENDING to STARTED
+const START_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bit `n` is set once hart `n` takes IPIs
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+/// The hart OpenSBI started us on, it keeps the console
+static BOOT_HART: AtomicUsize = AtomicUsize::new(0);
+/// Set by [halt_others], harts stop on the next IPI instead of taking calls
+static HALTING: AtomicBool = AtomicBool::new(false);
+static MAILBOXES: [Mutex<VecDeque<Call>>; MAX_HARTS] =
+    [const { Mutex::new(VecDeque::new()) }; MAX_HARTS];
+
+struct Call {
+    func: *const (dyn Fn() + Sync),
+    /// number of harts that still have to run `func`
+    pending: *const AtomicUsize,
+}
+
+// safety: both pointers are to the stack of the hart in [call_on], which waits for every call to
+// have finished before returning
+unsafe impl Send for Call {}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HartError {
+    #[error("sbi call failed: {0}")]
+    Sbi(#[from] SbiError),
+    #[error("hart#{0} was never brought up")]
+    NotBroughtUp(usize),
+    #[error("hart#{hartid} is {state:?}")]
+    WrongState { hartid: usize, state: HartState },
+    #[error("hart#{hartid} did not start in time")]
+    Timeout { hartid: usize },
+}
+
+/// Start a hart with its per-CPU block (see [percpu::alloc]), and wait until it runs
+pub fn start_hart(hartid: usize, cpu: usize) -> Result<(), HartError> {
+    // the hart starts with paging disabled, so it needs the physical address of its entry
+    let entry = vmem::virt_to_phys(_start_hart as *const () as usize);
+    hsm::start(hartid, entry, cpu)?;
+
+    let deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        match hsm::get_status(hartid)? {
+            HartState::Started => return Ok(()),
+            HartState::StartPending if Instant::now() >= deadline => {
+                return Err(HartError::Timeout { hartid });
+            }
+            HartState::StartPending => riscv::pause(),
+            state => return Err(HartError::WrongState { hartid, state }),
+        }
+    }
+}
+
+/// Start a hart that was parked with [stop] again
+#[allow(unused)]
+pub fn restart(hartid: usize) -> Result<(), HartError> {
+    let cpu = percpu::block(hartid).ok_or(HartError::NotBroughtUp(hartid))?;
+    start_hart(hartid, cpu)
+}
+
+/// Park the current hart, until another one brings it back with [restart]
+#[allow(unused)]
+pub fn stop() -> ! {
+    let me = riscv::hartid();
+    riscv::interrupt::disable();
+
+    // under our mailbox lock, so a call is either posted before we go offline, or not at all (see
+    // [call_on])
+    let mailbox = &MAILBOXES[me];
+    riscv::interrupt::free(|| {
+        let _mailbox = mailbox.lock();
+        ONLINE.fetch_and(!(1 << me), Ordering::AcqRel);
+    });
+
+    // whoever posted a call before we went offline is waiting for it
+    run_calls();
+
+    let error = hsm::stop();
+    panic!("could not stop hart#{me}: {error}");
+}
+
+/// Stop the current hart for good, without looking at its mailbox. For when the kernel cannot go
+/// on, nothing in here allocates or takes a lock
+pub fn halt() -> ! {
+    riscv::interrupt::disable();
+    ONLINE.fetch_and(!(1 << riscv::hartid()), Ordering::AcqRel);
+
+    let _ = hsm::stop();
+    riscv::pauseloop();
+}
+
+/// Make every other hart [halt] on its next IPI, and send them one. This does not wait for them,
+/// a hart that keeps interrupts off never gets to it
+pub fn halt_others() {
+    HALTING.store(true, Ordering::Release);
+
+    let others = others();
+    if others != 0 {
+        let _ = sbi::ipi::send_ipi(sbi::HartMask::from_mask(others));
+    }
+}
+
+/// Put the current hart in a low power state until an interrupt comes in. The SBI implementation
+/// can do more than `wfi` would, like power the hart down while keeping its state
+#[allow(unused)]
+pub fn suspend() -> Result<(), HartError> {
+    Ok(hsm::suspend(hsm::SUSPEND_RETENTIVE, 0, 0)?)
+}
+
+/// Mark the current hart as taking IPIs. It has to be able to take supervisor software interrupts
+pub fn set_online() {
+    let hartid = riscv::hartid();
+    assert!(hartid < MAX_HARTS, "hart#{hartid} is above MAX_HARTS");
+
+    ONLINE.fetch_or(1 << hartid, Ordering::AcqRel);
+}
+
+/// Remember the current hart as the one we booted on, device interrupts are routed to it
+pub fn set_boot_hart() {
+    BOOT_HART.store(riscv::hartid(), Ordering::Relaxed);
+}
+
+/// The hart we booted on
+pub fn boot_hart() -> usize {
+    BOOT_HART.load(Ordering::Relaxed)
+}
+
+/// Mask of every hart that takes IPIs
+pub fn online() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Mask of every hart that takes IPIs, other than the current one
+pub fn others() -> usize {
+    online() & !(1 << riscv::hartid())
+}
+
+/// Run `f` on every hart in the mask `harts`, and wait for all of them to finish. Harts that are not
+/// online are skipped, the current hart runs `f` right away. Cannot be called from trap context
+#[allow(unused)]
+pub fn call_on(harts: usize, f: &(dyn Fn() + Sync)) {
+    debug_assert!(!percpu::in_interrupt(), "cross-hart call from trap context");
+
+    let me = riscv::hartid();
+    let targets = harts & others();
+
+    let pending = AtomicUsize::new(targets.count_ones() as usize);
+    // safety: we do not return before every target has run the call, see [Call]
+    let func = unsafe { core::mem::transmute::<&(dyn Fn() + Sync), &'static (dyn Fn() + Sync)>(f) };
+
+    let mut posted = 0;
+    for (hartid, mailbox) in MAILBOXES.iter().enumerate() {
+        if targets & (1 << hartid) == 0 {
+            continue;
+        }
+
+        let call = Call {
+            func,
+            pending: &pending,
+        };
+
+        // the target could have stopped since we read `others()`. It goes offline under its
+        // mailbox lock, and does not look at its mailbox again after that
+        riscv::interrupt::free(|| {
+            let mut mailbox = mailbox.lock();
+            if online() & (1 << hartid) != 0 {
+                mailbox.push_back(call);
+                posted |= 1 << hartid;
+            } else {
+                pending.fetch_sub(1, Ordering::Release);
+            }
+        });
+    }
+
+    if posted != 0 {
+        sbi::ipi::send_ipi(sbi::HartMask::from_mask(posted))
+            .unwrap_or_else(|error| panic!("could not send an ipi to {posted:#x}: {error}"));
+    }
+
+    if harts & (1 << me) != 0 {
+        f();
+    }
+
+    // another hart could be waiting on us at the same time, so we keep taking calls ourselves
+    while pending.load(Ordering::Acquire) != 0 {
+        run_calls();
+        riscv::pause();
+    }
+}
+
+/// Run `f` on every hart that is online, the current one included
+#[allow(unused)]
+pub fn call_on_all(f: &(dyn Fn() + Sync)) {
+    call_on(online() | 1 << riscv::hartid(), f);
+}
+
+/// Called on a supervisor software interrupt
+pub fn handle_ipi() {
+    riscv::sip::clear_soft();
+    if HALTING.load(Ordering::Acquire) {
+        halt();
+    }
+
+    run_calls();
+}
+
+fn run_calls() {
+    let Some(mailbox) = MAILBOXES.get(riscv::hartid()) else {
+        return;
+    };
+
+    // the lock is not held while running a call, so other harts can keep posting to us
+    while let Some(call) = riscv::interrupt::free(|| mailbox.lock().pop_front()) {
+        unsafe {
+            (*call.func)();
+            (*call.pending).fetch_sub(1, Ordering::Release);
+        }
+    }
+}
+
+unsafe extern "C" {
+    fn _start_hart();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn hart_status() {
+        assert_eq!(
+            hsm::get_status(riscv::hartid()).unwrap(),
+            HartState::Started
+        );
+        assert!(matches!(restart(riscv::hartid()), Err(HartError::Sbi(_))));
+    }
+
+    #[test_case]
+    fn suspend_until_interrupt() {
+        // retentive suspend wakes up on any interrupt, like wfi
+        crate::time::after(Duration::from_millis(1), |_| {}, 0).unwrap();
+        suspend().unwrap();
+    }
+
+    #[test_case]
+    fn call_on_self() {
+        let calls = AtomicUsize::new(0);
+        let count = || {
+            calls.fetch_add(1, Ordering::Relaxed);
+        };
+
+        call_on(1 << riscv::hartid(), &count);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // harts that are not online are skipped, so this only runs on us
+        call_on(!others(), &count);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,126 @@
+//! Kernel stacks
+//!
+//! Every hart gets a slot of its own in a dedicated region of the address space. The stack sits at
+//! the top of the slot, with unmapped guard pages below it, so running off the end of a stack is a
+//! page fault instead of silently corrupting whatever comes next. The lowest page of the slot is
+//! the emergency stack, `ktrapvec` switches to it when the trap frame would land in the guard.
+//!
+//! ```text
+//! | emergency stack | guard pages | stack |
+//! ^ slot                                  ^ slot + SLOT_SIZE (top of the stack)
+//! ```
+
+use crate::allocator::FRAME_ALLOC;
+use crate::vmem::{KSTACKS_BASE, MapError, Mapper, Perms};
+use crate::{PAGE_SIZE, STACK_PAGES};
+
+/// Size of the region all kernel stacks live in, kernelvec.s gets it from main.rs
+pub const REGION_SIZE: usize = 0x4000_0000;
+/// Virtual memory set aside for a single hart, kernelvec.s and entry.s get it from main.rs
+pub const SLOT_SIZE: usize = 0x8000;
+pub const STACK_SIZE: usize = STACK_PAGES * PAGE_SIZE;
+
+// the guard pages sit between the emergency stack and the stack
+pub const GUARD_START: usize = PAGE_SIZE;
+pub const GUARD_END: usize = SLOT_SIZE - STACK_SIZE;
+
+// kernelvec.s relies on the slot being a power of two to find the guard pages
+const _: () = assert!(SLOT_SIZE.is_power_of_two() && GUARD_END > GUARD_START);
+
+/// Map a stack and an emergency stack for `hartid`, returns the top of the stack
+pub fn alloc(mapper: &mut Mapper, hartid: usize) -> Result<usize, MapError> {
+    assert!(
+        hartid < REGION_SIZE / SLOT_SIZE,
+        "no stack slot for hart {hartid}"
+    );
+    let slot = KSTACKS_BASE + hartid * SLOT_SIZE;
+
+    let mut frames = FRAME_ALLOC.lock();
+    let (stack, emergency) = match (frames.alloc_pages(STACK_PAGES), frames.alloc(0)) {
+        (Ok(stack), Ok(emergency)) => (stack, emergency),
+        (stack, emergency) => {
+            // hand back whichever one we did get
+            for frame in [stack, emergency].into_iter().flatten() {
+                let _ = frames.free(frame);
+            }
+            return Err(MapError::OutOfMemory);
+        }
+    };
+    drop(frames);
+
+    let mapped = mapper
+        .map(stack, slot + GUARD_END, Perms::READ_WRITE, STACK_PAGES)
+        .and_then(|()| mapper.map(emergency, slot, Perms::READ_WRITE, 1));
+
+    if let Err(err) = mapped {
+        // a map can fail halfway through, so we take down whatever made it into the slot
+        unmap_slot(mapper, slot);
+
+        let mut frames = FRAME_ALLOC.lock();
+        let _ = frames.free(stack);
+        let _ = frames.free(emergency);
+        return Err(err);
+    }
+
+    Ok(slot + SLOT_SIZE)
+}
+
+/// Unmap the stacks of `hartid` and hand their frames back. Nothing may run on them anymore
+pub fn free(mapper: &mut Mapper, hartid: usize) {
+    let slot = KSTACKS_BASE + hartid * SLOT_SIZE;
+
+    // both stacks are a single allocation, so only their first page is handed back
+    let frames = [slot + GUARD_END, slot].map(|page| mapper.translate(page));
+    unmap_slot(mapper, slot);
+
+    let mut allocator = FRAME_ALLOC.lock();
+    for (paddr, _) in frames.into_iter().flatten() {
+        let _ = allocator.free(paddr);
+    }
+}
+
+fn unmap_slot(mapper: &mut Mapper, slot: usize) {
+    for page in (slot..slot + SLOT_SIZE).step_by(PAGE_SIZE) {
+        if mapper.translate(page).is_some() {
+            let _ = mapper.unmap(page, 1);
+        }
+    }
+}
+
+/// The bounds of the stack slot of `hartid`, the emergency stack included
+pub fn slot(hartid: usize) -> (usize, usize) {
+    let slot = KSTACKS_BASE + hartid * SLOT_SIZE;
+    (slot, slot + SLOT_SIZE)
+}
+
+/// The hart whose stack slot `addr` is in, if any
+pub fn owner(addr: usize) -> Option<usize> {
+    let offset = addr.checked_sub(KSTACKS_BASE)?;
+    (offset < REGION_SIZE).then_some(offset / SLOT_SIZE)
+}
+
+/// The hart whose guard pages `addr` is in, if any
+pub fn guard_owner(addr: usize) -> Option<usize> {
+    let in_guard =
+        (GUARD_START..GUARD_END).contains(&(addr.wrapping_sub(KSTACKS_BASE) % SLOT_SIZE));
+    owner(addr).filter(|_| in_guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn guard_pages() {
+        let slot = KSTACKS_BASE + 3 * SLOT_SIZE;
+
+        assert_eq!(owner(slot + SLOT_SIZE - 8), Some(3));
+        assert_eq!(guard_owner(slot + SLOT_SIZE - 8), None);
+        assert_eq!(guard_owner(slot + GUARD_END - 8), Some(3));
+        assert_eq!(guard_owner(slot + GUARD_START), Some(3));
+        // the emergency stack is not part of the guard
+        assert_eq!(guard_owner(slot + 8), None);
+        assert_eq!(owner(KSTACKS_BASE - 8), None);
+        assert_eq!(owner(KSTACKS_BASE + REGION_SIZE), None);
+    }
+}
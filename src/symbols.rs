@@ -3,12 +3,20 @@ crate::include_asm!("symbols.s");
 unsafe extern "C" {
     pub static MEMTOP: usize;
     pub static ETEXT: usize;
+    pub static ERODATA: usize;
 
-    // pub static STACK_TOP: usize;
-    // pub static STACK_BOTTOM: usize;
+    // the symbol table backtraces are resolved against
+    pub static KSYMS_START: usize;
+    pub static KSYMS_END: usize;
 
-    // reserved for a "dma" stype allocator (contigous allocations)
-    pub static HEAP0_TOP: usize;
-    // reserved for a global_alloc which enables me to use `alloc`
-    pub static HEAP1_TOP: usize;
+    // the template of every hart's per-CPU block
+    pub static PERCPU_START: usize;
+    pub static PERCPU_END: usize;
+
+    // the boot stack, grows down from STACK_BOTTOM
+    pub static STACK_TOP: usize;
+    pub static STACK_BOTTOM: usize;
+
+    // the end of the kernel image and the boot stack, RAM past this point is free to use
+    pub static KERNEL_END: usize;
 }
@@ -6,16 +6,24 @@ MEMTOP: .dword __mem_top
 .global ETEXT
 ETEXT: .dword __etext
 
-# our rust code does not have any need to access these as of yet
-# .global STACK_TOP
-# STACK_TOP: .dword __stack_top
-# .global STACK_BOTTOM
-# STACK_BOTTOM: .dword __stack_bottom
+.global ERODATA
+ERODATA: .dword __erodata
 
-# the first heap, this is for very early stage init
-.global HEAP0_TOP
-HEAP0_TOP: .dword __heap0_top
+.global KSYMS_START
+KSYMS_START: .dword __ksyms_start
+.global KSYMS_END
+KSYMS_END: .dword __ksyms_end
 
-# the second heap, this is managed by a linked list allocator
-.global HEAP1_TOP
-HEAP1_TOP: .dword __heap1_top
+.global PERCPU_START
+PERCPU_START: .dword __percpu_start
+.global PERCPU_END
+PERCPU_END: .dword __percpu_end
+
+.global STACK_TOP
+STACK_TOP: .dword __stack_top
+.global STACK_BOTTOM
+STACK_BOTTOM: .dword __stack_bottom
+
+# everything after this is handed to the frame allocator (unless the fdt says otherwise)
+.global KERNEL_END
+KERNEL_END: .dword __kernel_end
@@ -0,0 +1,70 @@
+//! Locks that are safe to take from trap context
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use spin::{Mutex, MutexGuard};
+
+use crate::riscv::interrupt;
+
+/// A spin lock that keeps interrupts disabled on the current hart while it is held. A lock that
+/// an interrupt handler can take has to be one of these, or the handler could spin forever on a
+/// lock held by the code it interrupted
+pub struct IrqMutex<T>(Mutex<T>);
+
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    /// whether interrupts were enabled before we took the lock
+    enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self(Mutex::new(value))
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let enabled = interrupt::disable_local();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.0.lock()),
+            enabled,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let enabled = interrupt::disable_local();
+        match self.0.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                enabled,
+            }),
+            None => {
+                interrupt::restore_local(enabled);
+                None
+            }
+        }
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // safety: the guard is not used after this. It has to go before interrupts are enabled
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        interrupt::restore_local(self.enabled);
+    }
+}
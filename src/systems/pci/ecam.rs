@@ -1,6 +1,6 @@
 use super::pci_device::{Device, DeviceHeader};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EcamLocked {
     ecam: Ecam,
     bus: u8,
@@ -13,6 +13,11 @@ impl EcamLocked {
     //     self.ecam.address(self.bus, self.device, self.func, 0)
     // }
 
+    /// The bus, device and function number
+    pub fn location(&self) -> (u8, u8, u8) {
+        (self.bus, self.device, self.func)
+    }
+
     pub fn read<T>(&self, offset: u8) -> T {
         self.ecam.read(self.bus, self.device, self.func, offset)
     }
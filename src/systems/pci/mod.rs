@@ -3,13 +3,16 @@
 #![allow(unused)]
 
 mod ecam;
+mod msi;
 mod pci_device;
 
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 
-pub use self::{ecam::*, pci_device::*};
-use crate::vmem::{Mapper, Perms};
+use spin::Once;
+
+pub use self::{ecam::*, msi::*, pci_device::*};
+use crate::vmem::{self, Mapper, Perms};
 use crate::{PAGE_SIZE, round_down_by};
 
 const COMPATIBLE: &[&str] = &["pci-host-ecam-generic"];
@@ -26,6 +29,50 @@ const OFFSET_COMMAND: u8 = 0x4;
 // all six of these have a difference of 4 (bytes), as each field is 32-bits
 const OFFSET_BARS: [u8; 6] = [0x10, 0x14, 0x18, 0x1C, 0x20, 0x24];
 
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_VENDOR: u8 = 0x09;
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+/// Every device found at boot, drivers take theirs out of [PciSubsystem] but they stay in here
+static FOUND: Once<Vec<DeviceInfo>> = Once::new();
+
+/// What we know about a device, without being able to touch it
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceInfo {
+    pub bus: u8,
+    pub device: u8,
+    pub func: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class_code: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: HeaderType,
+}
+
+impl From<&Device> for DeviceInfo {
+    fn from(device: &Device) -> Self {
+        let (bus, dev, func) = device.ecam.location();
+
+        Self {
+            bus,
+            device: dev,
+            func,
+            vendor_id: device.header.vendor_id,
+            device_id: device.header.device_id,
+            class_code: device.header.class_code,
+            subclass: device.header.subclass,
+            prog_if: device.header.prog_if,
+            header_type: device.header.header_type,
+        }
+    }
+}
+
+/// Every device found at boot, empty if the subsystem is not initialised
+pub fn devices() -> &'static [DeviceInfo] {
+    FOUND.get().map_or(&[], Vec::as_slice)
+}
+
 #[derive(Debug)]
 pub struct PciSubsystem {
     mem: PciMemory,
@@ -38,9 +85,12 @@ impl PciSubsystem {
         let mem = PciMemory::parse_from_fdt(fdt)?;
         mem.map_memory(mapper);
 
-        let ecam = Ecam::init(mem.base_address);
+        let ecam = Ecam::init(vmem::phys_to_virt(mem.base_address));
 
-        let devices = enumerate_devices(ecam)
+        let devices = enumerate_devices(ecam);
+        FOUND.call_once(|| devices.iter().map(DeviceInfo::from).collect());
+
+        let devices = devices
             .into_iter()
             .map(|device| {
                 let vendor_id = device.vendor_id();
@@ -138,7 +188,11 @@ impl PciMemory {
         let next_addr = address + size;
 
         if next_addr < addr_max {
-            self.mmio_64_bit = Some(next_addr);
+            if is_64_bits {
+                self.mmio_64_bit = Some(next_addr);
+            } else {
+                self.mmio_32_bit = Some(next_addr);
+            }
             Some(address)
         } else {
             None
@@ -151,7 +205,7 @@ impl PciMemory {
 
         mapper.map(
             base_mem_addr,
-            base_mem_addr,
+            vmem::phys_to_virt(base_mem_addr),
             Perms::READ_WRITE,
             base_mem_pages,
         );
@@ -160,14 +214,16 @@ impl PciMemory {
             let mmio_size = mmio_max - mmio_addr;
             let mmio_pages = round_down_by(mmio_size, PAGE_SIZE) / PAGE_SIZE;
 
-            mapper.map(mmio_addr, mmio_addr, Perms::READ_WRITE, mmio_pages);
+            let vaddr = vmem::phys_to_virt(mmio_addr);
+            mapper.map(mmio_addr, vaddr, Perms::READ_WRITE, mmio_pages);
         }
 
         if let (Some(mmio_addr), Some(mmio_max)) = (self.mmio_32_bit, self.mmio_max_32_bit) {
             let mmio_size = mmio_max - mmio_addr;
             let mmio_pages = round_down_by(mmio_size, PAGE_SIZE) / PAGE_SIZE;
 
-            mapper.map(mmio_addr, mmio_addr, Perms::READ_WRITE, mmio_pages);
+            let vaddr = vmem::phys_to_virt(mmio_addr);
+            mapper.map(mmio_addr, vaddr, Perms::READ_WRITE, mmio_pages);
         }
     }
 }
@@ -0,0 +1,283 @@
+//! Message signalled interrupts (MSI and MSI-X)
+//!
+//! Instead of asserting a wired interrupt, a device writes a message to an address we pick. We
+//! point those writes at the IMSIC interrupt file of the hart a vector is routed to, the message
+//! being an identity allocated from the [Imsic].
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use super::{CAP_ID_MSI, CAP_ID_MSIX, Device, EcamLocked};
+use crate::drivers::DriverError;
+use crate::drivers::imsic::{Imsic, MsiSource};
+use crate::irq::Irq;
+use crate::vmem;
+
+// offsets into the MSI capability
+const MSI_CONTROL: u8 = 0x2;
+const MSI_ADDRESS_LO: u8 = 0x4;
+const MSI_ADDRESS_HI: u8 = 0x8;
+const MSI_DATA_32: u8 = 0x8;
+const MSI_DATA_64: u8 = 0xc;
+const MSI_MASK_32: u8 = 0xc;
+const MSI_MASK_64: u8 = 0x10;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+const MSI_CONTROL_64_BIT: u16 = 1 << 7;
+const MSI_CONTROL_PER_VECTOR_MASK: u16 = 1 << 8;
+
+// offsets into the MSI-X capability
+const MSIX_CONTROL: u8 = 0x2;
+const MSIX_TABLE: u8 = 0x4;
+
+const MSIX_CONTROL_TABLE_SIZE: u16 = 0x7ff;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_TABLE_BIR: u32 = 0b111;
+
+// every entry of the MSI-X table is 16 bytes
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS_LO: usize = 0x0;
+const MSIX_ENTRY_ADDRESS_HI: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_CONTROL: usize = 0xc;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy)]
+pub enum MsiCapability {
+    Msi {
+        offset: u8,
+        control: u16,
+    },
+    MsiX {
+        offset: u8,
+        table_size: usize,
+        table_bar: u8,
+        table_offset: usize,
+    },
+}
+
+impl MsiCapability {
+    /// Look for MSI-X first, and fall back to MSI
+    pub fn parse(device: &Device) -> Option<Self> {
+        if let Some(offset) = device.find_capability(CAP_ID_MSIX) {
+            let control = device.ecam.read::<u16>(offset + MSIX_CONTROL);
+            let table = device.ecam.read::<u32>(offset + MSIX_TABLE);
+
+            return Some(Self::MsiX {
+                offset,
+                table_size: (control & MSIX_CONTROL_TABLE_SIZE) as usize + 1,
+                table_bar: (table & MSIX_TABLE_BIR) as u8,
+                table_offset: (table & !MSIX_TABLE_BIR) as usize,
+            });
+        }
+
+        let offset = device.find_capability(CAP_ID_MSI)?;
+        let control = device.ecam.read::<u16>(offset + MSI_CONTROL);
+        Some(Self::Msi { offset, control })
+    }
+
+    /// Number of vectors a driver can request
+    pub fn max_vectors(&self) -> usize {
+        match self {
+            // multiple MSI messages need a block of identities next to each other, which the IMSIC
+            // does not hand out, so we only ever use one
+            Self::Msi { .. } => 1,
+            Self::MsiX { table_size, .. } => *table_size,
+        }
+    }
+
+    /// The BAR the MSI-X table is in. It has to be given an address before requesting vectors
+    pub fn table_bar(&self) -> Option<u8> {
+        match self {
+            Self::Msi { .. } => None,
+            Self::MsiX { table_bar, .. } => Some(*table_bar),
+        }
+    }
+}
+
+/// Interrupt vectors of a device, every one of them is an [Irq] of its own
+#[derive(Debug)]
+pub struct MsiVectors {
+    irqs: Vec<Irq>,
+}
+
+impl MsiVectors {
+    /// Set up `count` vectors of `device`, masked until their irq is requested. `bar_addrs` holds
+    /// the (physical) address of every BAR the driver allocated, and needs to include
+    /// [MsiCapability::table_bar]
+    pub fn request(
+        device: &Device,
+        count: usize,
+        bar_addrs: &BTreeMap<u8, usize>,
+    ) -> Result<Self, DriverError> {
+        if !Imsic::is_initialised() {
+            return Err(DriverError::DriverUninitialised);
+        }
+
+        let capability = MsiCapability::parse(device).ok_or(DriverError::InvalidDevice {
+            reason: "device does not support message signalled interrupts",
+        })?;
+
+        if count == 0 || count > capability.max_vectors() {
+            return Err(DriverError::InvalidDevice {
+                reason: "device does not have that many interrupt vectors",
+            });
+        }
+
+        let table = match capability {
+            MsiCapability::Msi { .. } => 0,
+            MsiCapability::MsiX {
+                table_bar,
+                table_offset,
+                ..
+            } => {
+                let address = bar_addrs.get(&table_bar).ok_or(DriverError::OtherError(
+                    "address for the msi-x table bar has not been allocated",
+                ))?;
+
+                // the bars hold physical addresses, we reach them through the direct map
+                vmem::phys_to_virt(*address + table_offset)
+            }
+        };
+
+        let entry = |vector| MsiEntry {
+            ecam: device.ecam.clone(),
+            capability,
+            table,
+            vector,
+        };
+
+        match capability {
+            MsiCapability::MsiX {
+                offset, table_size, ..
+            } => {
+                // keep the whole function masked while we mask every entry by itself
+                let control = device.ecam.read::<u16>(offset + MSIX_CONTROL);
+                let control = control | MSIX_CONTROL_ENABLE;
+                device
+                    .ecam
+                    .write(offset + MSIX_CONTROL, control | MSIX_CONTROL_FUNCTION_MASK);
+
+                for vector in 0..table_size {
+                    entry(vector).mask();
+                }
+
+                device
+                    .ecam
+                    .write(offset + MSIX_CONTROL, control & !MSIX_CONTROL_FUNCTION_MASK);
+            }
+            MsiCapability::Msi { offset, control } => {
+                // MSI stays off until the irq is requested, and only ever sends one message
+                let control = control & !(MSI_CONTROL_ENABLE | MSI_CONTROL_MULTIPLE_ENABLE);
+                device.ecam.write(offset + MSI_CONTROL, control);
+            }
+        }
+
+        // messages are memory writes, the device has to be allowed to make them
+        device.enable_bus_master();
+
+        let irqs = (0..count)
+            .map(|vector| Imsic::register_msi(Box::new(entry(vector))))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { irqs })
+    }
+
+    pub fn len(&self) -> usize {
+        self.irqs.len()
+    }
+
+    /// The irq behind `vector`, to be requested through [crate::irq]
+    pub fn irq(&self, vector: usize) -> Option<Irq> {
+        self.irqs.get(vector).copied()
+    }
+}
+
+/// A single vector, as the IMSIC sees it
+struct MsiEntry {
+    ecam: EcamLocked,
+    capability: MsiCapability,
+    /// virtual address of the MSI-X table
+    table: usize,
+    vector: usize,
+}
+
+impl MsiSource for MsiEntry {
+    fn compose(&self, address: usize, id: u32) {
+        match self.capability {
+            MsiCapability::MsiX { .. } => {
+                self.write_entry(MSIX_ENTRY_ADDRESS_LO, address as u32);
+                self.write_entry(MSIX_ENTRY_ADDRESS_HI, (address >> 32) as u32);
+                self.write_entry(MSIX_ENTRY_DATA, id);
+            }
+            MsiCapability::Msi { offset, control } => {
+                self.ecam.write(offset + MSI_ADDRESS_LO, address as u32);
+
+                if control & MSI_CONTROL_64_BIT != 0 {
+                    self.ecam
+                        .write(offset + MSI_ADDRESS_HI, (address >> 32) as u32);
+                    self.ecam.write(offset + MSI_DATA_64, id as u16);
+                } else {
+                    self.ecam.write(offset + MSI_DATA_32, id as u16);
+                }
+            }
+        }
+    }
+
+    fn mask(&self) {
+        self.set_masked(true);
+    }
+
+    fn unmask(&self) {
+        self.set_masked(false);
+    }
+}
+
+impl MsiEntry {
+    fn set_masked(&self, masked: bool) {
+        match self.capability {
+            MsiCapability::MsiX { .. } => {
+                let control = self.read_entry(MSIX_ENTRY_CONTROL);
+                let control = if masked {
+                    control | MSIX_ENTRY_MASKED
+                } else {
+                    control & !MSIX_ENTRY_MASKED
+                };
+
+                self.write_entry(MSIX_ENTRY_CONTROL, control);
+            }
+            MsiCapability::Msi { offset, control } => {
+                let per_vector_mask = control & MSI_CONTROL_PER_VECTOR_MASK != 0;
+
+                if per_vector_mask {
+                    let mask = match control & MSI_CONTROL_64_BIT {
+                        0 => offset + MSI_MASK_32,
+                        _ => offset + MSI_MASK_64,
+                    };
+                    self.ecam.write(mask, masked as u32);
+                }
+
+                // without per vector masking, the only way to mask our one vector is to turn MSI off
+                let control = self.ecam.read::<u16>(offset + MSI_CONTROL);
+                let control = match masked && !per_vector_mask {
+                    true => control & !MSI_CONTROL_ENABLE,
+                    false => control | MSI_CONTROL_ENABLE,
+                };
+                self.ecam.write(offset + MSI_CONTROL, control);
+            }
+        }
+    }
+
+    fn read_entry(&self, field: usize) -> u32 {
+        let address = self.table + self.vector * MSIX_ENTRY_SIZE + field;
+        unsafe { core::ptr::read_volatile(address as *const u32) }
+    }
+
+    fn write_entry(&self, field: usize, value: u32) {
+        let address = self.table + self.vector * MSIX_ENTRY_SIZE + field;
+        unsafe { core::ptr::write_volatile(address as *mut u32, value) };
+    }
+}
@@ -47,15 +47,51 @@ impl Device {
         self.ecam.write(super::OFFSET_COMMAND, cmd);
     }
 
-    pub fn get_capabilities<T, V: Extend<T>>(&self, list: &mut V) {
-        let offset = match self.header.header_type {
-            HeaderType::Pci2Cardbus => 0x14,
-            _ => 0x34,
-        };
+    /// Enable the device to initiate memory accesses of its own, like DMA and message signalled
+    /// interrupts
+    pub fn enable_bus_master(&self) {
+        let cmd = self.ecam.read::<u16>(super::OFFSET_COMMAND);
+        self.ecam.write(super::OFFSET_COMMAND, cmd | 0b100); // we turn ON bit 2
+    }
 
-        let pointer = self.ecam.read::<u8>(offset);
-        let cap_ptr = if pointer != 0 { Some(pointer) } else { None };
-        self.enum_capabilities(cap_ptr, list);
+    /// Read the data of every capability with id `cap_id`
+    pub fn get_capabilities<T, V: Extend<T>>(&self, cap_id: u8, list: &mut V) {
+        let mut ptr = self.capabilities_pointer();
+
+        while let Some(offset) = ptr {
+            // only read the data of capabilities we know the layout of
+            let cap = self.ecam.read::<super::Capabilities<()>>(offset);
+            if cap.cap_id == cap_id {
+                let cap = self.ecam.read::<super::Capabilities<T>>(offset);
+                list.extend(Some(cap.data));
+            }
+
+            ptr = if cap.next_cap != 0 {
+                Some(cap.next_cap)
+            } else {
+                None
+            };
+        }
+    }
+
+    /// Offset of the first capability with id `cap_id` in the configuration space
+    pub fn find_capability(&self, cap_id: u8) -> Option<u8> {
+        let mut ptr = self.capabilities_pointer();
+
+        while let Some(offset) = ptr {
+            let cap = self.ecam.read::<super::Capabilities<()>>(offset);
+            if cap.cap_id == cap_id {
+                return Some(offset);
+            }
+
+            ptr = if cap.next_cap != 0 {
+                Some(cap.next_cap)
+            } else {
+                None
+            };
+        }
+
+        None
     }
 
     pub fn get_bar_size(&self, bar_nr: u8) -> (bool, u32) {
@@ -80,15 +116,14 @@ impl Device {
         (is_64_bits, !(new_value & 0xFFFFFFF0) + 1)
     }
 
-    fn enum_capabilities<T, V: Extend<T>>(&self, ptr: Option<u8>, list: &mut V) {
-        if let Some(ptr) = ptr {
-            let cap = self.ecam.read::<super::Capabilities<T>>(ptr);
-            list.extend(Some(cap.data));
+    fn capabilities_pointer(&self) -> Option<u8> {
+        let offset = match self.header.header_type {
+            HeaderType::Pci2Cardbus => 0x14,
+            _ => 0x34,
+        };
 
-            if cap.next_cap != 0 {
-                self.enum_capabilities(Some(cap.next_cap), list);
-            }
-        }
+        let pointer = self.ecam.read::<u8>(offset);
+        if pointer != 0 { Some(pointer) } else { None }
     }
 }
 
@@ -0,0 +1,480 @@
+//! Time keeping and timers
+//!
+//! The `time` CSR counts the ticks of a clock shared by every hart, at the `timebase-frequency`
+//! found in `/cpus`. Each hart keeps a queue of timers and has its timer interrupt programmed for
+//! the earliest deadline in it, instead of taking a tick at a fixed interval.
+//!
+//! When every hart has the Sstc extension, the timer is programmed by writing `stimecmp` ourselves.
+//! Otherwise every re-arm is an ecall into the SBI implementation, which does it for us.
+//!
+//! Timer callbacks run in trap context, on the hart that added the timer. Like irq handlers, they
+//! cannot allocate or sleep.
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub use core::time::Duration;
+use spin::{Mutex, Once};
+
+use crate::MAX_HARTS;
+use crate::percpu;
+use crate::riscv::{self, sbi};
+
+/// Number of timers a single hart can have pending, the queue cannot grow in trap context
+const MAX_TIMERS: usize = 32;
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Ticks per second
+static TIMEBASE: Once<u64> = Once::new();
+/// Set if every hart can program its timer through `stimecmp`
+static SSTC: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+crate::per_cpu! {
+    static TIMERS: Mutex<Queue> = Mutex::new(Queue::new());
+}
+
+pub type Callback = fn(data: usize);
+
+#[derive(Debug, thiserror::Error)]
+pub enum TimerError {
+    #[error("the fdt does not have a timebase-frequency")]
+    NoTimebase,
+    #[error("the timebase frequency is not known yet")]
+    Uninitialised,
+    #[error("too many timers pending on hart {0}")]
+    QueueFull(usize),
+}
+
+/// Read the frequency of the `time` CSR from the fdt
+pub fn init(fdt: fdt::Fdt) -> Result<(), TimerError> {
+    let timebase = fdt
+        .find_node("/cpus")
+        .and_then(|cpus| cpus.property("timebase-frequency"))
+        .and_then(|frequency| frequency.as_usize())
+        .filter(|&frequency| frequency != 0)
+        .ok_or(TimerError::NoTimebase)?;
+
+    TIMEBASE.call_once(|| timebase as u64);
+
+    let sstc = fdt.cpus().all(|cpu| has_extension(cpu, "sstc"));
+    SSTC.store(sstc, Ordering::Relaxed);
+
+    let via = if sstc { "stimecmp" } else { "sbi" };
+    log::info!("[TIME] timebase frequency is {timebase}Hz, programming timers through {via}");
+    Ok(())
+}
+
+fn has_extension(cpu: fdt::Cpu, extension: &str) -> bool {
+    if let Some(extensions) = cpu.property("riscv,isa-extensions") {
+        return extensions
+            .value
+            .split(|&byte| byte == 0)
+            .any(|name| name == extension.as_bytes());
+    }
+
+    // older device trees only have the ISA string, with multi-letter extensions after underscores
+    cpu.property("riscv,isa")
+        .and_then(|isa| isa.as_str())
+        .is_some_and(|isa| isa.split('_').skip(1).any(|name| name == extension))
+}
+
+/// Program the timer of the current hart, every hart has to call this for itself
+pub fn inithart() {
+    riscv::interrupt::free(|| program(queue().lock().next_deadline()));
+}
+
+/// A point in time, measured by the `time` CSR. It never goes backwards, and is the same on every
+/// hart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(riscv::time() as u64)
+    }
+
+    #[allow(unused)]
+    pub fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Zero if `earlier` is actually later
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    #[allow(unused)]
+    pub fn elapsed(self) -> Duration {
+        Self::now().duration_since(self)
+    }
+
+    /// Time since the clock started counting, which is when the machine came up
+    pub fn since_boot(self) -> Duration {
+        ticks_to_duration(self.0)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration_to_ticks(duration)))
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Zero until the timebase is known
+fn ticks_to_duration(ticks: u64) -> Duration {
+    match TIMEBASE.get() {
+        Some(&timebase) => {
+            let nanos = (ticks % timebase) as u128 * NANOS_PER_SEC / timebase as u128;
+            Duration::new(ticks / timebase, nanos as u32)
+        }
+        None => Duration::ZERO,
+    }
+}
+
+fn duration_to_ticks(duration: Duration) -> u64 {
+    let timebase = TIMEBASE.get().copied().unwrap_or(0) as u128;
+    (duration.as_nanos() * timebase / NANOS_PER_SEC).min(u64::MAX as u128) as u64
+}
+
+/// Identifies a timer, to cancel it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id: TimerId,
+    deadline: Instant,
+    period: Option<Duration>,
+    callback: Callback,
+    data: usize,
+}
+
+struct Queue {
+    timers: [Option<Timer>; MAX_TIMERS],
+    /// the periodic timer whose callback is running, it goes back into the queue afterwards
+    /// unless it gets cancelled
+    running: Option<TimerId>,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Self {
+            timers: [None; MAX_TIMERS],
+            running: None,
+        }
+    }
+
+    fn insert(&mut self, timer: Timer) -> bool {
+        match self.timers.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(timer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, id: TimerId) -> bool {
+        if self.running == Some(id) {
+            self.running = None;
+            return true;
+        }
+
+        match self
+            .timers
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|t| t.id == id))
+        {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.timers
+            .iter()
+            .flatten()
+            .map(|timer| timer.deadline)
+            .min()
+    }
+
+    /// Take out the earliest timer, if its deadline has passed
+    fn pop_expired(&mut self, now: Instant) -> Option<Timer> {
+        let slot = self
+            .timers
+            .iter_mut()
+            .filter(|slot| slot.is_some_and(|timer| timer.deadline <= now))
+            .min_by_key(|slot| slot.map(|timer| timer.deadline))?;
+
+        slot.take()
+    }
+}
+
+fn queue() -> &'static Mutex<Queue> {
+    TIMERS.get()
+}
+
+/// Call `callback(data)` on the current hart once `deadline` has passed
+#[allow(unused)]
+pub fn at(deadline: Instant, callback: Callback, data: usize) -> Result<TimerId, TimerError> {
+    add(deadline, None, callback, data)
+}
+
+/// Call `callback(data)` on the current hart after `delay`
+#[allow(unused)]
+pub fn after(delay: Duration, callback: Callback, data: usize) -> Result<TimerId, TimerError> {
+    add(Instant::now() + delay, None, callback, data)
+}
+
+/// Call `callback(data)` on the current hart every `period`, until the timer is cancelled
+#[allow(unused)]
+pub fn every(period: Duration, callback: Callback, data: usize) -> Result<TimerId, TimerError> {
+    add(Instant::now() + period, Some(period), callback, data)
+}
+
+/// Stop a timer, returns false if it already went off (or was cancelled before)
+#[allow(unused)]
+pub fn cancel(id: TimerId) -> bool {
+    // the timer could be on any hart
+    riscv::interrupt::free(|| {
+        (0..MAX_HARTS)
+            .filter_map(|hartid| TIMERS.get_for(hartid))
+            .any(|queue| queue.lock().remove(id))
+    })
+}
+
+fn add(
+    deadline: Instant,
+    period: Option<Duration>,
+    callback: Callback,
+    data: usize,
+) -> Result<TimerId, TimerError> {
+    if !TIMEBASE.is_completed() {
+        return Err(TimerError::Uninitialised);
+    }
+
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let timer = Timer {
+        id,
+        deadline,
+        period,
+        callback,
+        data,
+    };
+
+    riscv::interrupt::free(|| {
+        let mut queue = queue().lock();
+        if !queue.insert(timer) {
+            return Err(TimerError::QueueFull(riscv::hartid()));
+        }
+
+        program(queue.next_deadline());
+        Ok(id)
+    })
+}
+
+/// Called on a supervisor timer interrupt
+pub fn handle_timer() {
+    let queue = queue();
+
+    loop {
+        let now = Instant::now();
+        // callbacks run without the lock held, so they can add and cancel timers themselves
+        let Some(timer) = queue.lock().pop_expired(now) else {
+            break;
+        };
+
+        queue.lock().running = timer.period.map(|_| timer.id);
+        (timer.callback)(timer.data);
+
+        let mut queue = queue.lock();
+        if let Some(period) = timer.period
+            && queue.running.take() == Some(timer.id)
+        {
+            // if we fell behind, we skip the periods we missed instead of catching up on them. The
+            // callback could have taken a while, so we go by the time it is now
+            let now = Instant::now();
+            let deadline = match timer.deadline + period {
+                deadline if deadline <= now => now + period,
+                deadline => deadline,
+            };
+
+            // the timer was just taken out of the queue, so there is room for it
+            queue.insert(Timer { deadline, ..timer });
+        }
+    }
+
+    program(queue.lock().next_deadline());
+}
+
+/// Have the timer of the current hart go off at `deadline`. Without a deadline it is pushed as
+/// far out as it goes, which also clears the pending interrupt
+fn program(deadline: Option<Instant>) {
+    rearm(deadline.map_or(usize::MAX, |deadline| deadline.0 as usize));
+}
+
+fn rearm(value: usize) {
+    if SSTC.load(Ordering::Relaxed) {
+        riscv::stimecmp::write(value);
+    } else {
+        sbi::time::set_timer(value)
+            .unwrap_or_else(|error| panic!("could not program the timer: {error}"));
+    }
+}
+
+/// Average number of cycles it takes to re-arm the timer, on each path the hart has
+#[allow(unused)]
+#[derive(Debug)]
+pub struct RearmLatency {
+    pub sbi: usize,
+    /// only if the hart has Sstc
+    pub stimecmp: Option<usize>,
+}
+
+/// Re-arm the timer `iterations` times through SBI, and as many times through `stimecmp`, to
+/// compare the two. The timer is left the way it was
+#[allow(unused)]
+pub fn measure_rearm(iterations: usize) -> RearmLatency {
+    let iterations = iterations.max(1);
+
+    riscv::interrupt::free(|| {
+        let deadline = queue().lock().next_deadline();
+        let value = deadline.map_or(usize::MAX, |deadline| deadline.0 as usize);
+
+        let measure = |rearm: fn(usize)| {
+            let start = riscv::cycle();
+            for _ in 0..iterations {
+                rearm(value);
+            }
+            (riscv::cycle() - start) / iterations
+        };
+
+        let latency = RearmLatency {
+            sbi: measure(|value| {
+                let _ = sbi::time::set_timer(value);
+            }),
+            stimecmp: SSTC
+                .load(Ordering::Relaxed)
+                .then(|| measure(riscv::stimecmp::write)),
+        };
+
+        program(deadline);
+        latency
+    })
+}
+
+/// Block the current hart for at least `duration`. Interrupts keep being taken while we wait, so
+/// this cannot be called from trap context, or with interrupts disabled
+#[allow(unused)]
+pub fn sleep_for(duration: Duration) {
+    debug_assert!(!percpu::in_interrupt(), "sleeping in trap context");
+
+    let deadline = Instant::now() + duration;
+    let woken = AtomicBool::new(false);
+
+    let wake = |data| {
+        // safety: the flag is on the stack of the sleeping hart, which waits for this
+        let woken = unsafe { &*(data as *const AtomicBool) };
+        woken.store(true, Ordering::Release);
+    };
+
+    if at(deadline, wake, &woken as *const _ as usize).is_err() {
+        // no room for another timer, so we spin instead
+        while Instant::now() < deadline {
+            riscv::pause();
+        }
+        return;
+    }
+
+    while !woken.load(Ordering::Acquire) {
+        // the timer cannot go off between checking the flag and going to sleep
+        riscv::interrupt::free(|| {
+            if !woken.load(Ordering::Acquire) {
+                riscv::wfi();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    fn count(data: usize) {
+        let counter = unsafe { &*(data as *const AtomicUsize) };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn conversions() {
+        let timebase = *TIMEBASE.get().unwrap();
+
+        assert_eq!((Instant(0) + Duration::from_secs(1)).ticks(), timebase);
+        assert_eq!(
+            Instant(timebase * 3 / 2).since_boot(),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(Instant(0) - Instant(timebase), Duration::ZERO);
+    }
+
+    #[test_case]
+    fn rearm_latency() {
+        let latency = measure_rearm(16);
+
+        assert!(latency.sbi > 0);
+        assert!(latency.stimecmp.is_none_or(|cycles| cycles > 0));
+        // the timer still works afterwards
+        sleep_for(Duration::from_millis(1));
+    }
+
+    #[test_case]
+    fn sleeps() {
+        let start = Instant::now();
+        sleep_for(Duration::from_millis(5));
+        assert!(start.elapsed() >= Duration::from_millis(5));
+    }
+
+    #[test_case]
+    fn periodic_timers() {
+        let counter = AtomicUsize::new(0);
+        let data = &counter as *const _ as usize;
+
+        let timer = every(Duration::from_millis(1), count, data).unwrap();
+        sleep_for(Duration::from_millis(20));
+        assert!(cancel(timer));
+
+        let fired = counter.load(Ordering::Relaxed);
+        assert!(fired >= 2, "periodic timer only went off {fired} times");
+
+        sleep_for(Duration::from_millis(5));
+        assert_eq!(counter.load(Ordering::Relaxed), fired);
+    }
+
+    #[test_case]
+    fn cancelled_timers() {
+        let counter = AtomicUsize::new(0);
+        let data = &counter as *const _ as usize;
+
+        let timer = after(Duration::from_millis(1), count, data).unwrap();
+        assert!(cancel(timer));
+        assert!(!cancel(timer));
+
+        sleep_for(Duration::from_millis(5));
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+    }
+}
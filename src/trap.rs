@@ -1,38 +1,176 @@
+use core::arch::asm;
+use core::sync::atomic::Ordering;
+
+use crate::backtrace;
+use crate::drivers::imsic::Imsic;
+use crate::drivers::plic::Plic;
+use crate::monitor;
+use crate::percpu;
 use crate::riscv;
 use crate::riscv::interrupt::{Exception, Interrupt, Trap};
-use crate::riscv::sbi;
+use crate::smp;
+use crate::stack;
+use crate::time;
+use crate::vmem::{self, Access};
+use crate::writer;
+
+/// [probe_store] keeps this in t5 while it stores to the address in t4. A store fault at that
+/// address skips the store instead of halting the kernel, and clears t5 to tell the probe. Living
+/// in registers, the probe state belongs to the hart that is probing
+const PROBE_MAGIC: usize = 0x7072_6f62_6500_0000;
 
-/// This is the value that is set in stvec. Loading and saving of registers is handled by the
-/// compiler, we dont have to do it manually. Allocates 480 bytes on the stack, and saves in the
-/// order that RISC-V spec defines it's registers. (18.2 RVG Calling Convention)
+/// Called by ktrapvec (the value in stvec), which saves the registers and trap CSRs in a
+/// [riscv::Frame] on the stack. Whatever the handlers leave in the frame is what we return to
 #[unsafe(no_mangle)]
-extern "C" fn kerneltrap(frame: *const riscv::Frame) {
-    let cause = riscv::interrupt::cause();
-    let frame = unsafe { &*frame };
+extern "C" fn kerneltrap(frame: *mut riscv::Frame) {
+    let frame = unsafe { &mut *frame };
+    let cause = riscv::interrupt::decode(frame.scause);
+    let cpu = percpu::cpu();
 
     match cause {
-        Trap::Interrupt(interrupt) => handle_interrupt(interrupt),
-        Trap::Exception(exception) => handle_exception(exception, frame),
+        Trap::Interrupt(interrupt) => {
+            cpu.stats.interrupts.fetch_add(1, Ordering::Relaxed);
+            cpu.irq_depth.fetch_add(1, Ordering::Relaxed);
+            handle_interrupt(interrupt);
+            cpu.irq_depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        Trap::Exception(exception) => {
+            cpu.stats.exceptions.fetch_add(1, Ordering::Relaxed);
+            handle_exception(exception, frame);
+        }
     };
 }
 
 fn handle_interrupt(interrupt: Interrupt) {
+    let stats = &percpu::cpu().stats;
+
     match interrupt {
-        Interrupt::SupervisorSoft => todo!("software interrupt"),
-        Interrupt::SupervisorTimer => reset_timer(),
-        Interrupt::SupervisorExternal => todo!("external interrupt"),
+        Interrupt::SupervisorSoft => {
+            stats.ipis.fetch_add(1, Ordering::Relaxed);
+            smp::handle_ipi();
+        }
+        Interrupt::SupervisorTimer => {
+            stats.timers.fetch_add(1, Ordering::Relaxed);
+            time::handle_timer();
+        }
+        Interrupt::SupervisorExternal => {
+            if !Imsic::handle_interrupt() && !Plic::handle_interrupt(riscv::hartid()) {
+                log::warn!("external interrupt, but there is no interrupt controller");
+            }
+        }
     };
 }
 
-fn handle_exception(exception: Exception, frame: &riscv::Frame) {
-    log::error!("TRAP: SEPC: {:#x}", ::riscv::register::sepc::read());
+fn handle_exception(exception: Exception, frame: &mut riscv::Frame) {
+    let stval = frame.stval;
+
+    let access = match exception {
+        Exception::LoadPageFault => Some(Access::Load),
+        Exception::StorePageFault => Some(Access::Store),
+        Exception::InstructionPageFault => Some(Access::Execute),
+        _ => None,
+    };
+
+    let fault = access.map(|access| (access, vmem::handle_fault(stval, access)));
+    if let Some((_, Ok(()))) = fault {
+        return;
+    }
+
+    let is_store_fault = matches!(exception, Exception::StorePageFault | Exception::StoreFault);
+    if is_store_fault && frame.t5 == PROBE_MAGIC && frame.t4 == stval {
+        frame.t5 = 0;
+        skip_instruction(frame);
+        return;
+    }
+
+    // only the tests put breakpoints in on purpose, anywhere else they are a bug
+    #[cfg(test)]
+    if exception == Exception::Breakpoint {
+        log::debug!("TRAP: BREAKPOINT at {:#x}", frame.sepc);
+        skip_instruction(frame);
+        return;
+    }
+
+    // there is no going back from here, and the code we interrupted could be holding the console
+    writer::set_fatal();
+
+    // the trap frame still fit on the stack, but the code we interrupted ran into the guard pages
+    if access.is_some()
+        && let Some(hart) = stack::guard_owner(stval)
+    {
+        stack_overflow(hart, frame);
+    }
+
+    if let Some((access, Err(err))) = fault {
+        log::error!("TRAP: PAGE FAULT: {access:?} at {stval:#x}: {err}");
+        vmem::print_walk(stval);
+    }
+
+    log::error!("TRAP: SEPC: {:#x}", frame.sepc);
     log::error!("TRAP: EXCEPTION: {exception:?}");
     frame.pretty_print();
+    backtrace::print_trap(frame.sepc, frame.fp);
+
+    monitor::enter_fatal();
+}
+
+/// Called by ktrapvec, on the emergency stack, when the trap frame would have ended up in the
+/// guard pages of a kernel stack
+#[unsafe(no_mangle)]
+extern "C" fn kernel_stack_overflow(frame: *const riscv::Frame) -> ! {
+    let frame = unsafe { &*frame };
+    let hart = stack::owner(frame.sp()).expect("overflowed a stack outside of the stack region");
+
+    stack_overflow(hart, frame);
+}
+
+fn stack_overflow(hart: usize, frame: &riscv::Frame) -> ! {
+    writer::set_fatal();
+    log::error!("kernel stack overflow on hart {hart}");
+    log::error!("TRAP: SEPC: {:#x}", frame.sepc);
+    log::error!("TRAP: STVAL: {:#x}", frame.stval);
+    frame.pretty_print();
+    backtrace::print_trap(frame.sepc, frame.fp);
 
     riscv::pauseloop();
 }
 
-pub fn reset_timer() {
-    // log::debug!("timer reset");
-    sbi::time::set_timer(riscv::time() + crate::INTERVAL)
+/// Move sepc past the instruction that trapped, so we do not execute it again on return
+fn skip_instruction(frame: &mut riscv::Frame) {
+    // the lowest two bits of a 32 bit instruction are always set, compressed ones are 16 bits
+    let parcel = unsafe { core::ptr::read_volatile(frame.sepc as *const u16) };
+    let len = if parcel & 0b11 == 0b11 { 4 } else { 2 };
+
+    frame.sepc += len;
+}
+
+/// Write `value` to `addr`, returning false if the store faulted. This lets us check that a
+/// mapping is enforced without bringing the kernel down
+#[allow(unused)]
+pub fn probe_store(addr: *mut u8, value: u8) -> bool {
+    let probe: usize;
+    // safety: if the address is not writable, the trap handler skips over the store
+    unsafe {
+        asm!(
+            "sb {value}, 0(t4)",
+            value = in(reg) value,
+            in("t4") addr,
+            inout("t5") PROBE_MAGIC => probe,
+            options(nostack),
+        )
+    };
+
+    probe == PROBE_MAGIC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn breakpoints_are_skipped() {
+        // the handler moves sepc past the ebreak in the frame, so we come back right after it
+        riscv::ebreak();
+        riscv::ebreak();
+    }
 }
@@ -0,0 +1,680 @@
+//! Terminals: a line discipline on top of a character device
+//!
+//! A [Tty] takes bytes from a [CharDevice] and hands them out as edited lines in canonical mode,
+//! or as they come in raw mode. Escape sequences are decoded into [Key]s either way. Output (echo
+//! included) goes through the kernel console, which ends up on the same device: the UART once it is
+//! up, the firmware console through the SBI before that.
+//!
+//! Line editing only knows ASCII, other bytes are left out of the line.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::drivers::uart::CharDriver;
+use crate::irq;
+use crate::riscv::{
+    self,
+    sbi::{self, Extension},
+};
+use crate::time::{self, Duration, Instant};
+
+/// Lines kept for the arrow keys to go through
+const HISTORY: usize = 32;
+/// How often a device without interrupts is checked for input
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Bytes taken from the device at once
+const INPUT_CHUNK: usize = 16;
+
+const CTRL_A: u8 = 0x01;
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const CTRL_E: u8 = 0x05;
+const CTRL_H: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const ESC: u8 = 0x1b;
+const DEL: u8 = 0x7f;
+
+static SBI_CONSOLE: SbiConsole = SbiConsole;
+
+/// Somewhere bytes come in from
+pub trait CharDevice: Sync {
+    /// Move bytes that came in into `buf`, without waiting for more. Returns how many there were
+    fn read(&self, buf: &mut [u8]) -> usize;
+
+    /// Like [CharDevice::read], but takes input straight from the device, for when interrupts are
+    /// not coming anymore
+    fn poll(&self, buf: &mut [u8]) -> usize {
+        self.read(buf)
+    }
+
+    /// Whether there is input waiting, checked with interrupts disabled before going to sleep
+    fn pending(&self) -> bool;
+
+    /// Whether input raises an interrupt on the current hart, otherwise we poll for it
+    fn interrupt_driven(&self) -> bool;
+}
+
+/// The firmware console, through DBCN or the legacy `console_getchar`. It can only be polled
+pub struct SbiConsole;
+
+impl CharDevice for SbiConsole {
+    fn read(&self, buf: &mut [u8]) -> usize {
+        if sbi::has_extension(Extension::Dbcn) {
+            return sbi::dbcn::read(buf).unwrap_or(0);
+        }
+
+        buf.iter_mut()
+            .map_while(|slot| sbi::legacy::console_getchar().map(|byte| *slot = byte))
+            .count()
+    }
+
+    fn pending(&self) -> bool {
+        false
+    }
+
+    fn interrupt_driven(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// input is edited a line at a time, and handed out once enter is pressed
+    Canonical,
+    /// input is handed out as it comes in, only printable ASCII is echoed
+    Raw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum TtyError {
+    #[error("interrupted")]
+    Interrupted,
+    #[error("end of input")]
+    EndOfFile,
+    #[error("the tty is not in canonical mode")]
+    NotCanonical,
+}
+
+/// A key press, with escape sequences and control characters decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// anything that is not one of the keys below
+    Char(u8),
+    Enter,
+    Backspace,
+    Delete,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    /// Ctrl-C
+    Interrupt,
+    /// Ctrl-D
+    EndOfFile,
+    /// Ctrl-U
+    KillLine,
+}
+
+/// Turns bytes into [Key]s, one byte at a time
+#[derive(Debug, Default)]
+struct Decoder {
+    state: Escape,
+    /// a terminal can send `\r\n` for enter, which should not be two of them
+    after_cr: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    #[default]
+    None,
+    /// got ESC
+    Start,
+    /// got `ESC [`, and the first parameter so far
+    Csi(u8),
+    /// past the first parameter of a CSI sequence, the rest are modifiers we do not care about
+    CsiRest(u8),
+    /// got `ESC O`
+    Ss3,
+}
+
+impl Decoder {
+    /// The key `byte` finishes, if any
+    fn feed(&mut self, byte: u8) -> Option<Key> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+
+        match (self.state, byte) {
+            (Escape::None, ESC) => self.state = Escape::Start,
+            (Escape::None, b'\n') if after_cr => {}
+            (Escape::None, _) => return Some(Self::control(byte)),
+
+            (Escape::Start, b'[') => self.state = Escape::Csi(0),
+            (Escape::Start, b'O') => self.state = Escape::Ss3,
+            // not a sequence we know, the byte after ESC is dropped with it
+            (Escape::Start, _) => self.state = Escape::None,
+
+            (Escape::Csi(param), b'0'..=b'9') => {
+                self.state = Escape::Csi(param.saturating_mul(10).saturating_add(byte - b'0'))
+            }
+            (Escape::Csi(param), b';') => self.state = Escape::CsiRest(param),
+            (Escape::CsiRest(_), b'0'..=b'9' | b';') => {}
+            (Escape::Csi(param) | Escape::CsiRest(param), 0x40..=0x7e) => {
+                self.state = Escape::None;
+                return match (byte, param) {
+                    (b'~', 1 | 7) => Some(Key::Home),
+                    (b'~', 3) => Some(Key::Delete),
+                    (b'~', 4 | 8) => Some(Key::End),
+                    (b'~', _) => None,
+                    (byte, _) => Self::cursor(byte),
+                };
+            }
+            // a broken sequence, start over
+            (Escape::Csi(_) | Escape::CsiRest(_), _) => self.state = Escape::None,
+
+            (Escape::Ss3, _) => {
+                self.state = Escape::None;
+                return Self::cursor(byte);
+            }
+        }
+
+        None
+    }
+
+    /// The final byte of a cursor key sequence
+    fn cursor(byte: u8) -> Option<Key> {
+        match byte {
+            b'A' => Some(Key::Up),
+            b'B' => Some(Key::Down),
+            b'C' => Some(Key::Right),
+            b'D' => Some(Key::Left),
+            b'H' => Some(Key::Home),
+            b'F' => Some(Key::End),
+            _ => None,
+        }
+    }
+
+    fn control(byte: u8) -> Key {
+        match byte {
+            b'\r' | b'\n' => Key::Enter,
+            DEL | CTRL_H => Key::Backspace,
+            CTRL_A => Key::Home,
+            CTRL_C => Key::Interrupt,
+            CTRL_D => Key::EndOfFile,
+            CTRL_E => Key::End,
+            CTRL_U => Key::KillLine,
+            byte => Key::Char(byte),
+        }
+    }
+}
+
+/// A terminal on a [CharDevice]
+pub struct Tty {
+    device: &'static dyn CharDevice,
+    /// never wait for interrupts, see [Tty::polled_console]
+    polled: bool,
+    mode: Mode,
+    echo: bool,
+    decoder: Decoder,
+    /// bytes taken from the device, but not handed out yet
+    input: [u8; INPUT_CHUNK],
+    start: usize,
+    end: usize,
+    /// a line [Tty::read] edited, that did not fit in the buffer it was given
+    cooked: VecDeque<u8>,
+    /// oldest first
+    history: VecDeque<String>,
+}
+
+/// Where the cursor is in a line being edited
+struct Line<'a> {
+    prompt: &'a str,
+    bytes: Vec<u8>,
+    cursor: usize,
+}
+
+impl Tty {
+    pub fn new(device: &'static dyn CharDevice) -> Self {
+        Self {
+            device,
+            polled: false,
+            mode: Mode::Canonical,
+            echo: true,
+            decoder: Decoder::default(),
+            input: [0; INPUT_CHUNK],
+            start: 0,
+            end: 0,
+            cooked: VecDeque::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    /// A tty on the UART if its driver is up, on the firmware console otherwise
+    pub fn console() -> Self {
+        match CharDriver::instance() {
+            Some(uart) => Self::new(uart),
+            None => Self::new(&SBI_CONSOLE),
+        }
+    }
+
+    /// Like [Tty::console], but it busy waits for input instead of sleeping. For when interrupts are
+    /// off for good, after a panic or in trap context
+    pub fn polled_console() -> Self {
+        Self {
+            polled: true,
+            ..Self::console()
+        }
+    }
+
+    #[allow(unused)]
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    #[allow(unused)]
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.decoder = Decoder::default();
+    }
+
+    #[allow(unused)]
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// The lines entered so far, oldest first
+    #[allow(unused)]
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    /// Wait for input, and move it into `buf`. In canonical mode that is a whole line, ending in
+    /// `\n`, which can take more than one call to get through. In raw mode it is whatever came in
+    #[allow(unused)]
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, TtyError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.mode == Mode::Raw {
+            buf[0] = self.next_byte();
+            let mut read = 1;
+            while read < buf.len() && self.start < self.end {
+                buf[read] = self.input[self.start];
+                self.start += 1;
+                read += 1;
+            }
+
+            // escape sequences and control characters would act on the terminal when echoed
+            buf[..read]
+                .iter()
+                .filter(|byte| matches!(byte, b' '..=b'~'))
+                .for_each(|&byte| self.echo_bytes(&[byte]));
+            return Ok(read);
+        }
+
+        if self.cooked.is_empty() {
+            let line = self.read_line("")?;
+            self.cooked.extend(line.bytes());
+            self.cooked.push_back(b'\n');
+        }
+
+        let read = buf.len().min(self.cooked.len());
+        for (slot, byte) in buf.iter_mut().zip(self.cooked.drain(..read)) {
+            *slot = byte;
+        }
+        Ok(read)
+    }
+
+    /// Wait for the next key, whatever the mode. Nothing is echoed
+    pub fn read_key(&mut self) -> Key {
+        loop {
+            let byte = self.next_byte();
+            if let Some(key) = self.decoder.feed(byte) {
+                return key;
+            }
+        }
+    }
+
+    /// Print `prompt`, and let a line be edited until enter is pressed. Ctrl-C gives up on the
+    /// line, Ctrl-D on an empty line is the end of input
+    pub fn read_line(&mut self, prompt: &str) -> Result<String, TtyError> {
+        if self.mode != Mode::Canonical {
+            return Err(TtyError::NotCanonical);
+        }
+
+        crate::print!("{prompt}");
+
+        let mut line = Line {
+            prompt,
+            bytes: Vec::new(),
+            cursor: 0,
+        };
+        // where we are in the history, history.len() being the line we are typing
+        let mut browsing = self.history.len();
+        // the line we were typing, while we look through the history
+        let mut draft = Vec::new();
+
+        loop {
+            match self.read_key() {
+                Key::Enter => break,
+                Key::Interrupt => {
+                    self.echo_str("^C\n");
+                    return Err(TtyError::Interrupted);
+                }
+                Key::EndOfFile if line.bytes.is_empty() => {
+                    self.echo_str("\n");
+                    return Err(TtyError::EndOfFile);
+                }
+                Key::EndOfFile | Key::Delete => {
+                    if line.cursor < line.bytes.len() {
+                        line.bytes.remove(line.cursor);
+                        self.redraw(&line);
+                    }
+                }
+                Key::Backspace => {
+                    if line.cursor > 0 {
+                        line.cursor -= 1;
+                        line.bytes.remove(line.cursor);
+                        self.redraw(&line);
+                    }
+                }
+                Key::KillLine => {
+                    line.bytes.clear();
+                    line.cursor = 0;
+                    self.redraw(&line);
+                }
+                Key::Left => {
+                    let to = line.cursor.saturating_sub(1);
+                    self.move_cursor(&mut line, to);
+                }
+                Key::Right => {
+                    let to = line.cursor + 1;
+                    self.move_cursor(&mut line, to);
+                }
+                Key::Home => self.move_cursor(&mut line, 0),
+                Key::End => self.move_cursor(&mut line, usize::MAX),
+                Key::Up => {
+                    if browsing > 0 {
+                        if browsing == self.history.len() {
+                            draft = core::mem::take(&mut line.bytes);
+                        }
+                        browsing -= 1;
+                        line.bytes = self.history[browsing].as_bytes().to_vec();
+                        line.cursor = line.bytes.len();
+                        self.redraw(&line);
+                    }
+                }
+                Key::Down => {
+                    if browsing < self.history.len() {
+                        browsing += 1;
+                        line.bytes = match self.history.get(browsing) {
+                            Some(entry) => entry.as_bytes().to_vec(),
+                            None => core::mem::take(&mut draft),
+                        };
+                        line.cursor = line.bytes.len();
+                        self.redraw(&line);
+                    }
+                }
+                Key::Char(byte @ b' '..=b'~') => {
+                    line.bytes.insert(line.cursor, byte);
+                    line.cursor += 1;
+
+                    if line.cursor == line.bytes.len() {
+                        self.echo_bytes(&[byte]);
+                    } else {
+                        self.redraw(&line);
+                    }
+                }
+                // other control characters, and anything that is not ASCII
+                Key::Char(_) => {}
+            }
+        }
+
+        self.echo_str("\n");
+
+        // only ASCII ever makes it into the line
+        let line = String::from_utf8(line.bytes).unwrap_or_default();
+        if !line.trim().is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+
+        Ok(line)
+    }
+
+    /// Like [Tty::read_line], but the line goes into `buf` and nothing is allocated, for when the
+    /// heap cannot be trusted anymore. Only backspace and Ctrl-U edit the line, there is no history,
+    /// and what does not fit in `buf` is left out
+    pub fn read_line_into<'a>(
+        &mut self,
+        prompt: &str,
+        buf: &'a mut [u8],
+    ) -> Result<&'a str, TtyError> {
+        if self.mode != Mode::Canonical {
+            return Err(TtyError::NotCanonical);
+        }
+
+        crate::print!("{prompt}");
+
+        let mut len = 0;
+        loop {
+            match self.read_key() {
+                Key::Enter => break,
+                Key::Interrupt => {
+                    self.echo_str("^C\n");
+                    return Err(TtyError::Interrupted);
+                }
+                Key::EndOfFile if len == 0 => {
+                    self.echo_str("\n");
+                    return Err(TtyError::EndOfFile);
+                }
+                Key::Backspace if len > 0 => {
+                    len -= 1;
+                    self.echo_str("\x08 \x08");
+                }
+                Key::KillLine => {
+                    len = 0;
+                    self.echo_fmt(format_args!("\r{prompt}\x1b[K"));
+                }
+                Key::Char(byte @ b' '..=b'~') if len < buf.len() => {
+                    buf[len] = byte;
+                    len += 1;
+                    self.echo_bytes(&[byte]);
+                }
+                _ => {}
+            }
+        }
+
+        self.echo_str("\n");
+
+        // only ASCII ever makes it into the line
+        Ok(core::str::from_utf8(&buf[..len]).unwrap_or_default())
+    }
+
+    fn move_cursor(&self, line: &mut Line, to: usize) {
+        let to = to.min(line.bytes.len());
+
+        if to < line.cursor {
+            self.echo_fmt(format_args!("\x1b[{}D", line.cursor - to));
+        } else if to > line.cursor {
+            self.echo_fmt(format_args!("\x1b[{}C", to - line.cursor));
+        }
+        line.cursor = to;
+    }
+
+    /// Print the whole line again, and put the cursor back where it was
+    fn redraw(&self, line: &Line) {
+        // only ASCII ever makes it into the line
+        let text = core::str::from_utf8(&line.bytes).unwrap_or_default();
+        self.echo_fmt(format_args!("\r{}{text}\x1b[K", line.prompt));
+
+        let behind = line.bytes.len() - line.cursor;
+        if behind > 0 {
+            self.echo_fmt(format_args!("\x1b[{behind}D"));
+        }
+    }
+
+    fn echo_str(&self, s: &str) {
+        self.echo_fmt(format_args!("{s}"));
+    }
+
+    fn echo_bytes(&self, bytes: &[u8]) {
+        bytes
+            .iter()
+            .for_each(|&byte| self.echo_fmt(format_args!("{}", byte as char)));
+    }
+
+    fn echo_fmt(&self, args: core::fmt::Arguments) {
+        if self.echo {
+            crate::writer::_print(args);
+        }
+    }
+
+    /// The next byte from the device, waiting for one if there is none
+    fn next_byte(&mut self) -> u8 {
+        while self.start == self.end {
+            self.start = 0;
+            self.end = match self.polled {
+                true => self.device.poll(&mut self.input),
+                false => self.device.read(&mut self.input),
+            };
+
+            if self.end == 0 {
+                self.wait();
+            }
+        }
+
+        let byte = self.input[self.start];
+        self.start += 1;
+        byte
+    }
+
+    /// Wait until the device could have input
+    fn wait(&self) {
+        if self.polled {
+            // no timer interrupt is coming to wake us up either
+            let deadline = Instant::now() + POLL_INTERVAL;
+            while Instant::now() < deadline {
+                riscv::pause();
+            }
+            return;
+        }
+
+        if !self.device.interrupt_driven() {
+            time::sleep_for(POLL_INTERVAL);
+            return;
+        }
+
+        // same as irq::idle, the check and going to sleep cannot be interrupted
+        irq::run_deferred();
+        riscv::interrupt::free(|| {
+            if !self.device.pending() {
+                riscv::wfi();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Hands out a fixed script of bytes, as if they were typed
+    struct Script {
+        bytes: &'static [u8],
+        at: AtomicUsize,
+    }
+
+    impl CharDevice for Script {
+        fn read(&self, buf: &mut [u8]) -> usize {
+            let at = self.at.load(Ordering::Relaxed);
+            let len = buf.len().min(self.bytes.len() - at);
+            assert!(len > 0, "the tty read past the end of the script");
+
+            buf[..len].copy_from_slice(&self.bytes[at..at + len]);
+            self.at.store(at + len, Ordering::Relaxed);
+            len
+        }
+
+        fn pending(&self) -> bool {
+            true
+        }
+
+        fn interrupt_driven(&self) -> bool {
+            true
+        }
+    }
+
+    fn tty(script: &'static [u8]) -> Tty {
+        let device = alloc::boxed::Box::leak(alloc::boxed::Box::new(Script {
+            bytes: script,
+            at: AtomicUsize::new(0),
+        }));
+
+        let mut tty = Tty::new(device);
+        tty.set_echo(false);
+        tty
+    }
+
+    #[test_case]
+    fn escape_sequences() {
+        let mut tty = tty(b"a\x1b[A\x1bOD\x1b[3~\x1b[1;5C\x1b[4~\r\n\x7f\x03");
+        let keys = [
+            Key::Char(b'a'),
+            Key::Up,
+            Key::Left,
+            Key::Delete,
+            Key::Right,
+            Key::End,
+            Key::Enter,
+            Key::Backspace,
+            Key::Interrupt,
+        ];
+
+        for key in keys {
+            assert_eq!(tty.read_key(), key);
+        }
+    }
+
+    #[test_case]
+    fn line_editing() {
+        let mut tty = tty(b"helo\x1b[Dl\r\x15abc\x7f\x7fx\x1b[Hy\r\x1b[A\x1b[A\r\x03\x04");
+
+        assert_eq!(tty.read_line("").as_deref(), Ok("hello"));
+        assert_eq!(tty.read_line("").as_deref(), Ok("yax"));
+        // up twice goes back past the previous line
+        assert_eq!(tty.read_line("").as_deref(), Ok("hello"));
+        assert_eq!(tty.read_line(""), Err(TtyError::Interrupted));
+        assert_eq!(tty.read_line(""), Err(TtyError::EndOfFile));
+
+        let history: Vec<&str> = tty.history().collect();
+        assert_eq!(history, ["hello", "yax", "hello"]);
+    }
+
+    #[test_case]
+    fn fixed_buffer_lines() {
+        let mut tty = tty(b"abc\x7fd\x1b[D\r\x15toolong\r\x04");
+        let mut buf = [0; 4];
+
+        assert_eq!(tty.read_line_into("", &mut buf), Ok("abd"));
+        assert_eq!(tty.read_line_into("", &mut buf), Ok("tool"));
+        assert_eq!(tty.read_line_into("", &mut buf), Err(TtyError::EndOfFile));
+    }
+
+    #[test_case]
+    fn raw_mode() {
+        let mut tty = tty(b"ab\x03");
+        tty.set_mode(Mode::Raw);
+
+        let mut buf = [0; 8];
+        assert_eq!(tty.read(&mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"ab\x03");
+        assert_eq!(tty.read_line(""), Err(TtyError::NotCanonical));
+    }
+}
@@ -1,11 +1,54 @@
-use core::alloc::Layout;
+use core::fmt;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::{PAGE_SIZE, allocator::BitMapAlloc, riscv};
+use crate::riscv::{self, sbi};
+use crate::{PAGE_SIZE, allocator::FRAME_ALLOC, smp};
 
 const NO_KPTBL: usize = 0xdead_babe;
+// read by entry.s, when secondary harts switch to the kernel's page table
+#[unsafe(no_mangle)]
 static PAGE_TABLE: AtomicUsize = AtomicUsize::new(NO_KPTBL);
 
+/// All of physical memory is mapped starting at this address, at a fixed offset. This is the start
+/// of the upper half of the Sv39 address space
+pub const PHYS_OFFSET: usize = 0xffff_ffc0_0000_0000;
+/// End of the direct map, we support up to 128 GiB of physical address space
+pub const DIRECT_MAP_END: usize = PHYS_OFFSET + 0x20_0000_0000;
+/// Large heap allocations get their address space from this region, and are mapped lazily. See
+/// [crate::allocator::vheap]
+pub const KHEAP_BASE: usize = DIRECT_MAP_END;
+pub const KHEAP_SIZE: usize = 0x10_0000_0000;
+/// Kernel stacks live in this 1 GiB region, see [crate::stack]
+pub const KSTACKS_BASE: usize = 0xffff_ffff_4000_0000;
+/// The kernel image is linked at this address and above (see linker.ld)
+pub const KERNEL_BASE: usize = 0xffff_ffff_8000_0000;
+/// Difference between the address the kernel is linked at, and where it is loaded
+pub const KERNEL_OFFSET: usize = 0xffff_ffff_0000_0000;
+
+/// Get the address physical memory at `paddr` can be accessed through, in the direct map
+#[inline]
+pub const fn phys_to_virt(paddr: usize) -> usize {
+    paddr + PHYS_OFFSET
+}
+
+/// Get the physical address behind `vaddr`. Addresses in the kernel image and the direct map are
+/// at a fixed offset, anything else is looked up in the kernel's page table
+#[inline]
+pub fn virt_to_phys(vaddr: usize) -> usize {
+    try_virt_to_phys(vaddr).unwrap_or_else(|| panic!("{vaddr:#x} is not mapped"))
+}
+
+/// Like [virt_to_phys], but `None` if `vaddr` is not mapped (or there is no page table yet)
+pub fn try_virt_to_phys(vaddr: usize) -> Option<usize> {
+    if vaddr >= KERNEL_BASE {
+        Some(vaddr - KERNEL_OFFSET)
+    } else if (PHYS_OFFSET..DIRECT_MAP_END).contains(&vaddr) {
+        Some(vaddr - PHYS_OFFSET)
+    } else {
+        translate(vaddr).map(|(paddr, _)| paddr)
+    }
+}
+
 bitflags::bitflags! {
     #[derive(Debug, Clone, Copy)]
     pub struct Perms: usize {
@@ -13,6 +56,7 @@ bitflags::bitflags! {
         // WRITE without READ is an invalid state
         const READ_WRITE = Self::READ.bits() | 1 << 2 ;
         const EXEC = 1 << 3;
+        const READ_EXEC = Self::READ.bits() | Self::EXEC.bits();
         const USER = 1 << 4;
     }
 }
@@ -23,15 +67,44 @@ pub enum MapError {
     InvalidPtr { ptr: usize },
     #[error("Remap detected when mapping memory")]
     Remap,
+    #[error("virtual address {vaddr:#x} is not mapped")]
+    NotMapped { vaddr: usize },
+    #[error("could not allocate a page table")]
+    OutOfMemory,
 }
 
-/// A struct that holds a reference to an allocator and the root page table. This allows drivers to
-/// map pages, without having to worry about anything outside their scope
-pub struct Mapper<'a> {
-    table: &'a mut [PTEntry; 512],
+/// The kind of access that caused a page fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Load,
+    Store,
+    Execute,
 }
 
-impl<'a> Mapper<'a> {
+#[derive(Debug, thiserror::Error)]
+pub enum FaultError {
+    #[error("{vaddr:#x} is not part of any mapped region")]
+    Unmapped { vaddr: usize },
+    #[error("{access:?} is not allowed on {vaddr:#x}, mapped as {perms:?}")]
+    Protection {
+        vaddr: usize,
+        access: Access,
+        perms: Perms,
+    },
+    #[error("could not map the faulting page: {0}")]
+    Map(#[from] MapError),
+}
+
+/// A struct that holds the root page table. This allows drivers to map pages, without having to
+/// worry about anything outside their scope
+pub struct Mapper {
+    /// physical address of the root page table
+    root: usize,
+}
+
+impl Mapper {
+    /// Map `pages` pages of physical memory starting at `paddr` to `vaddr`. Megapages (2 MiB) and
+    /// gigapages (1 GiB) are used automatically, wherever both addresses are suitably aligned
     pub fn map(
         &mut self,
         paddr: usize,
@@ -40,9 +113,84 @@ impl<'a> Mapper<'a> {
         pages: usize,
     ) -> Result<(), MapError> {
         // log::info!("[MAPPER] paddr={paddr:#x} vaddr={vaddr:#x} perms={perms:?} pages={pages}");
-        map(self.table, paddr, vaddr, perms, pages)?;
+        map(self.root, paddr, vaddr, perms, pages)?;
         Ok(())
     }
+
+    /// Remove the mappings of `pages` pages starting at `vaddr`. Page tables that end up empty are
+    /// handed back to the frame allocator. The mapped memory itself is not freed.
+    pub fn unmap(&mut self, vaddr: usize, pages: usize) -> Result<(), MapError> {
+        assert!(vaddr.is_multiple_of(PAGE_SIZE));
+
+        let end = vaddr + pages * PAGE_SIZE;
+        let mut va = vaddr;
+
+        while va < end {
+            let (_, size) = unmap(self.root, 2, va, end)?;
+            riscv::sfence_vma_addr(va);
+            va += size;
+        }
+
+        self.shootdown(vaddr, end - vaddr);
+        Ok(())
+    }
+
+    /// Change the permissions of `pages` already mapped pages starting at `vaddr`
+    #[allow(unused)]
+    pub fn protect(&mut self, vaddr: usize, perms: Perms, pages: usize) -> Result<(), MapError> {
+        assert!(vaddr.is_multiple_of(PAGE_SIZE));
+
+        let end = vaddr + pages * PAGE_SIZE;
+        let mut va = vaddr;
+        let mut reduced = false;
+
+        while va < end {
+            let (pte, level) = lookup(self.root, va).ok_or(MapError::NotMapped { vaddr: va })?;
+            let size = level_size(level);
+
+            // only part of a megapage is changed, so we break it up into smaller pages first
+            if !va.is_multiple_of(size) || va + size > end {
+                split(pte, level)?;
+                continue;
+            }
+
+            reduced |= !perms.contains(pte.get_perms());
+
+            pte.set_inner_from_pa(pte.get_physical_addr());
+            pte.set_perms(perms);
+            pte.set_valid(true);
+
+            riscv::sfence_vma_addr(va);
+            va += size;
+        }
+
+        // other harts can keep using stale entries that allow more, not ones that allow less
+        if reduced {
+            self.shootdown(vaddr, end - vaddr);
+        }
+
+        Ok(())
+    }
+
+    /// Flush `size` bytes starting at `vaddr` from the TLBs of the other harts, if they run on this
+    /// page table. Our own TLB is flushed by the caller
+    fn shootdown(&self, vaddr: usize, size: usize) {
+        let others = smp::others();
+        if others == 0 || self.root != PAGE_TABLE.load(Ordering::Relaxed) {
+            return;
+        }
+
+        sbi::rfence::remote_sfence_vma(sbi::HartMask::from_mask(others), vaddr, size)
+            .unwrap_or_else(|error| panic!("could not shoot down {vaddr:#x}: {error}"));
+    }
+
+    /// Look up the physical address and permissions `vaddr` is mapped to
+    pub fn translate(&self, vaddr: usize) -> Option<(usize, Perms)> {
+        let (pte, level) = lookup(self.root, vaddr)?;
+        let offset = vaddr & (level_size(level) - 1);
+
+        Some((pte.get_physical_addr() + offset, pte.get_perms()))
+    }
 }
 
 #[repr(C)]
@@ -73,44 +221,192 @@ impl PTEntry {
         (self.inner >> 10) << 12
     }
 
+    fn get_perms(&self) -> Perms {
+        Perms::from_bits_truncate(self.inner)
+    }
+
+    /// A valid entry with any of R, W or X set points to a page, otherwise it points to the next
+    /// level of the page table
+    fn is_leaf(&self) -> bool {
+        self.is_valid() && (self.inner & 0b1110) != 0
+    }
+
     fn clear(&mut self) {
         self.inner = 0
     }
 }
 
-pub fn init(balloc: &mut BitMapAlloc) -> Mapper<'_> {
-    let tbl_addr = balloc.alloc(1);
+/// Try to resolve a page fault at `vaddr` in the kernel's address space. Only lazily mapped memory
+/// (large heap allocations) can be faulted in, anything else is a bug in the kernel
+pub fn handle_fault(vaddr: usize, access: Access) -> Result<(), FaultError> {
+    if PAGE_TABLE.load(Ordering::Relaxed) == NO_KPTBL {
+        return Err(FaultError::Unmapped { vaddr });
+    }
+
+    if (KHEAP_BASE..KHEAP_BASE + KHEAP_SIZE).contains(&vaddr) && access != Access::Execute {
+        return crate::allocator::vheap::handle_fault(vaddr);
+    }
+
+    match kernel_mapper().translate(vaddr) {
+        Some((_, perms)) => Err(FaultError::Protection {
+            vaddr,
+            access,
+            perms,
+        }),
+        None => Err(FaultError::Unmapped { vaddr }),
+    }
+}
+
+/// Log the page table entries the MMU walks through to translate `vaddr`, using the page table that
+/// is currently active on this hart
+pub fn print_walk(vaddr: usize) {
+    log::error!(
+        "page table walk for {vaddr:#x}, root at {:#x}:",
+        active_root()
+    );
+    walk_entries(vaddr, |step| log::error!("  {step}"));
+}
+
+/// A page table entry the MMU went through, see [walk_entries]
+#[derive(Debug, Clone, Copy)]
+pub struct WalkStep {
+    pub level: usize,
+    pub index: usize,
+    /// the raw entry
+    pub entry: usize,
+    pub valid: bool,
+    pub leaf: bool,
+    /// of the page for a leaf, of the next table otherwise
+    pub paddr: usize,
+    pub perms: Perms,
+}
+
+impl fmt::Display for WalkStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match (self.valid, self.leaf) {
+            (false, _) => "invalid",
+            (true, true) => "leaf",
+            (true, false) => "table",
+        };
+
+        write!(
+            f,
+            "L{}[{:3}] = {:#018x} ({kind}, {:#x} {:?})",
+            self.level, self.index, self.entry, self.paddr, self.perms
+        )
+    }
+}
+
+/// The root of the page table that is currently active on this hart
+pub fn active_root() -> usize {
+    (riscv::satp::read() & ((1 << 44) - 1)) << 12
+}
+
+/// Call `visit` with every entry the MMU walks through to translate `vaddr`, using the page table
+/// that is currently active on this hart. Does not allocate, so it is fine in trap context
+pub fn walk_entries(vaddr: usize, mut visit: impl FnMut(&WalkStep)) {
+    let mut table = table_at(active_root());
+
+    for level in [2, 1, 0] {
+        let index = idx_for_vaddr(level, vaddr);
+        let pte = &table[index];
+
+        let step = WalkStep {
+            level,
+            index,
+            entry: pte.inner,
+            valid: pte.is_valid(),
+            leaf: pte.is_leaf(),
+            paddr: pte.get_physical_addr(),
+            perms: pte.get_perms(),
+        };
+        visit(&step);
+
+        if !step.valid || step.leaf {
+            return;
+        }
+
+        table = table_at(step.paddr);
+    }
+}
+
+/// Like [Mapper::translate] on the kernel's page table, but `None` if there is none yet
+pub fn translate(vaddr: usize) -> Option<(usize, Perms)> {
+    if PAGE_TABLE.load(Ordering::Relaxed) == NO_KPTBL {
+        return None;
+    }
+
+    kernel_mapper().translate(vaddr)
+}
+
+/// A mapper for the kernel's page table. Callers have to make sure they do not race each other on
+/// the same part of the address space
+pub fn kernel_mapper() -> Mapper {
+    let root = PAGE_TABLE.load(Ordering::Relaxed);
+    assert_ne!(root, NO_KPTBL, "vmem is not initialised");
+
+    Mapper { root }
+}
+
+pub fn init() -> Mapper {
+    let tbl_addr = alloc_table().expect("could not allocate root page table");
     PAGE_TABLE.store(tbl_addr, Ordering::Relaxed);
 
-    // safety: we know that table_addr contains 4096 bytes, so this is safe
-    let table = unsafe { &mut *(tbl_addr as *mut [PTEntry; 512]) };
+    Mapper { root: tbl_addr }
+}
+
+/// Allocate a zeroed page for a page table, and return its physical address
+fn alloc_table() -> Result<usize, MapError> {
+    let addr = FRAME_ALLOC
+        .lock()
+        .alloc(0)
+        .map_err(|_| MapError::OutOfMemory)?;
 
-    Mapper { table }
+    // pages from the frame allocator are not zeroed, and a garbage page table is no good
+    unsafe { core::ptr::write_bytes(phys_to_virt(addr) as *mut u8, 0, PAGE_SIZE) };
+    Ok(addr)
+}
+
+/// Get the page table stored at the given physical address
+fn table_at(paddr: usize) -> &'static mut [PTEntry; 512] {
+    // safety: page tables are always a full page, allocated by `alloc_table`
+    unsafe { &mut *(phys_to_virt(paddr) as *mut [PTEntry; 512]) }
 }
 
 fn map(
-    root: &mut [PTEntry; 512],
+    root: usize,
     paddr: usize,
     vaddr: usize,
     perms: Perms,
     pages: usize,
 ) -> Result<(), MapError> {
-    assert!((vaddr & 4096).is_multiple_of(4096));
+    assert!(vaddr.is_multiple_of(PAGE_SIZE) && paddr.is_multiple_of(PAGE_SIZE));
     assert!(pages > 0);
 
-    for i in 0..pages {
-        let offset = 4096 * i;
+    let size = pages * PAGE_SIZE;
+    let mut offset = 0;
+
+    while offset < size {
         let va = vaddr + offset;
         let pa = paddr + offset;
 
+        // we use the largest page both addresses are aligned to, that still fits in what is left
+        let level = [2, 1, 0]
+            .into_iter()
+            .find(|&level| {
+                let len = level_size(level);
+                va.is_multiple_of(len) && pa.is_multiple_of(len) && offset + len <= size
+            })
+            .unwrap_or(0);
+
         let pte = unsafe {
-            let ptr = walk(root, va);
+            let ptr = walk(root, va, level)?;
             ptr.as_mut()
                 .ok_or(MapError::InvalidPtr { ptr: ptr as usize })?
         };
 
-        // the walk function does not modify the page table entries on level 0. So if the valid bit
-        // is flipped, the user has most likely mapped an overlapping region
+        // the walk function does not modify the entry on the level we asked for. So if the valid
+        // bit is flipped, the user has most likely mapped an overlapping region
         if pte.is_valid() {
             return Err(MapError::Remap);
         }
@@ -118,40 +414,122 @@ fn map(
         pte.set_inner_from_pa(pa);
         pte.set_perms(perms);
         pte.set_valid(true);
+
+        offset += level_size(level);
     }
 
     Ok(())
 }
 
-fn walk(mut pagetable: &mut [PTEntry; 512], vaddr: usize) -> *mut PTEntry {
-    for level in [2, 1].iter() {
-        let idx = idx_for_vaddr(*level, vaddr);
+/// Walk down to the entry for `vaddr` on `level`, allocating page tables along the way
+fn walk(root: usize, vaddr: usize, level: usize) -> Result<*mut PTEntry, MapError> {
+    let mut pagetable = table_at(root);
+
+    for current in (level + 1..=2).rev() {
+        let idx = idx_for_vaddr(current, vaddr);
         let pte = &mut pagetable[idx];
 
+        // there is a megapage in the way
+        if pte.is_leaf() {
+            return Err(MapError::Remap);
+        }
+
         if pte.is_valid() {
             let pa = pte.get_physical_addr();
-            pagetable = unsafe { &mut *(pa as *mut [PTEntry; 512]) };
+            pagetable = table_at(pa);
         } else {
-            let new_table_addr = unsafe {
-                let layout = Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE);
-                let addr = alloc::alloc::alloc_zeroed(layout);
-                addr as usize
-            };
-
-            let new_table = unsafe { &mut *(new_table_addr as *mut [PTEntry; 512]) };
-
-            for entry in new_table.iter_mut() {
-                entry.clear()
-            }
+            let new_table_addr = alloc_table()?;
 
             pte.set_inner_from_pa(new_table_addr);
             pte.set_valid(true);
 
-            pagetable = new_table;
+            pagetable = table_at(new_table_addr);
         }
     }
 
-    &mut pagetable[idx_for_vaddr(0, vaddr)]
+    Ok(&mut pagetable[idx_for_vaddr(level, vaddr)])
+}
+
+/// Like [walk], but does not allocate any page tables. Returns the leaf entry mapping `vaddr`,
+/// along with the level it was found on
+fn lookup(root: usize, vaddr: usize) -> Option<(&'static mut PTEntry, usize)> {
+    let mut pagetable = table_at(root);
+
+    for level in [2, 1, 0] {
+        let pte = &mut pagetable[idx_for_vaddr(level, vaddr)];
+
+        if !pte.is_valid() {
+            return None;
+        }
+
+        if pte.is_leaf() {
+            return Some((pte, level));
+        }
+
+        pagetable = table_at(pte.get_physical_addr());
+    }
+
+    // a non-leaf entry on level 0 is not a valid page table
+    None
+}
+
+/// Turn a megapage on `level` into a table of 512 pages one level down, with the same permissions
+fn split(pte: &mut PTEntry, level: usize) -> Result<(), MapError> {
+    assert!(level > 0 && pte.is_leaf());
+
+    let table_addr = alloc_table()?;
+    let base = pte.get_physical_addr();
+    let perms = pte.get_perms();
+
+    for (i, entry) in table_at(table_addr).iter_mut().enumerate() {
+        entry.set_inner_from_pa(base + i * level_size(level - 1));
+        entry.set_perms(perms);
+        entry.set_valid(true);
+    }
+
+    pte.set_inner_from_pa(table_addr);
+    pte.set_valid(true);
+    Ok(())
+}
+
+/// Clear the leaf entry for `vaddr` in the table at `level`, without going past `end`. Returns
+/// true if the table is empty afterwards (so the caller can free it), and the number of bytes
+/// that were unmapped
+fn unmap(table: usize, level: usize, vaddr: usize, end: usize) -> Result<(bool, usize), MapError> {
+    let pagetable = table_at(table);
+    let pte = &mut pagetable[idx_for_vaddr(level, vaddr)];
+
+    // a non-leaf entry on level 0 is not a valid page table
+    if !pte.is_valid() || (level == 0 && !pte.is_leaf()) {
+        return Err(MapError::NotMapped { vaddr });
+    }
+
+    let size = level_size(level);
+    let covered = vaddr.is_multiple_of(size) && vaddr + size <= end;
+
+    // only part of a megapage is unmapped, so we break it up into smaller pages first
+    if pte.is_leaf() && !covered {
+        split(pte, level)?;
+    }
+
+    let unmapped = if pte.is_leaf() {
+        pte.clear();
+        size
+    } else {
+        let table_addr = pte.get_physical_addr();
+        let (empty, unmapped) = unmap(table_addr, level - 1, vaddr, end)?;
+
+        if empty {
+            pte.clear();
+            // the table was allocated by alloc_table, so this cannot fail
+            let _ = FRAME_ALLOC.lock().free(table_addr);
+        }
+
+        unmapped
+    };
+
+    let empty = pagetable.iter().all(|entry| !entry.is_valid());
+    Ok((empty, unmapped))
 }
 
 pub fn inithart() {
@@ -170,3 +548,144 @@ pub fn inithart() {
 fn idx_for_vaddr(level: usize, va: usize) -> usize {
     (va >> (12 + (9 * level))) & 0x1FF
 }
+
+/// Size of the memory mapped by a leaf entry on the given level (4 KiB, 2 MiB or 1 GiB)
+#[inline]
+const fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trap;
+
+    #[test_case]
+    fn rodata_is_read_only() {
+        // the last byte of .rodata, we only ever get a raw pointer to it from the linker
+        let addr = (unsafe { crate::symbols::ERODATA } - 1) as *mut u8;
+        let before = unsafe { core::ptr::read_volatile(addr) };
+        assert!(!trap::probe_store(addr, !before));
+
+        // it is not writable through the direct map either
+        let alias = phys_to_virt(virt_to_phys(addr as usize)) as *mut u8;
+        assert!(!trap::probe_store(alias, !before));
+
+        assert_eq!(unsafe { core::ptr::read_volatile(addr) }, before);
+    }
+
+    #[test_case]
+    fn direct_map() {
+        let paddr = 0x8020_1234;
+        assert_eq!(virt_to_phys(phys_to_virt(paddr)), paddr);
+
+        // the kernel image is reachable through both its link address and the direct map
+        let kernel = &PAGE_TABLE as *const _ as usize;
+        assert!(kernel >= KERNEL_BASE);
+        let direct = phys_to_virt(virt_to_phys(kernel)) as *const AtomicUsize;
+        assert_eq!(
+            unsafe { (*direct).load(Ordering::Relaxed) },
+            PAGE_TABLE.load(Ordering::Relaxed)
+        );
+    }
+
+    #[test_case]
+    fn map_translate_unmap() {
+        // we use a table of our own, so we do not mess with the kernel's mappings
+        let root = alloc_table().unwrap();
+        let mut mapper = Mapper { root };
+
+        let free_before = FRAME_ALLOC.lock().free_pages();
+
+        let paddr = 0x8765_4000;
+        let vaddr = 0x20_0000_0000;
+        mapper.map(paddr, vaddr, Perms::READ_WRITE, 2).unwrap();
+
+        let (pa, perms) = mapper.translate(vaddr + PAGE_SIZE + 0x123).unwrap();
+        assert_eq!(pa, paddr + PAGE_SIZE + 0x123);
+        assert_eq!(perms.bits(), Perms::READ_WRITE.bits());
+        assert!(mapper.translate(vaddr + 2 * PAGE_SIZE).is_none());
+        assert!(matches!(
+            mapper.map(paddr, vaddr, Perms::READ, 1),
+            Err(MapError::Remap)
+        ));
+
+        mapper.protect(vaddr, Perms::READ, 1).unwrap();
+        assert_eq!(
+            mapper.translate(vaddr).unwrap().1.bits(),
+            Perms::READ.bits()
+        );
+        assert_eq!(
+            mapper.translate(vaddr + PAGE_SIZE).unwrap().1.bits(),
+            Perms::READ_WRITE.bits()
+        );
+
+        mapper.unmap(vaddr, 2).unwrap();
+        assert!(mapper.translate(vaddr).is_none());
+        assert!(mapper.unmap(vaddr, 1).is_err());
+
+        // both intermediate tables should have been handed back
+        assert_eq!(FRAME_ALLOC.lock().free_pages(), free_before);
+        FRAME_ALLOC.lock().free(root).unwrap();
+    }
+
+    #[test_case]
+    fn megapages() {
+        let root = alloc_table().unwrap();
+        let mut mapper = Mapper { root };
+
+        let free_before = FRAME_ALLOC.lock().free_pages();
+
+        // a gigapage, followed by a megapage and a single regular page
+        const MEGA: usize = 512;
+        const GIGA: usize = 512 * 512;
+        let paddr = 0x1_0000_0000;
+        let vaddr = 0x40_0000_0000;
+        mapper
+            .map(paddr, vaddr, Perms::READ_WRITE, GIGA + MEGA + 1)
+            .unwrap();
+
+        // the root table, and one table each for level 1 and level 0
+        assert_eq!(FRAME_ALLOC.lock().free_pages(), free_before - 2);
+
+        let probe = vaddr + GIGA * PAGE_SIZE + 0x12_3456;
+        assert_eq!(
+            mapper.translate(probe).unwrap().0,
+            paddr + GIGA * PAGE_SIZE + 0x12_3456
+        );
+        assert_eq!(
+            mapper.translate(vaddr + 0x3000_0000).unwrap().0,
+            paddr + 0x3000_0000
+        );
+        assert!(matches!(
+            mapper.map(paddr, vaddr + 0x1000, Perms::READ, 1),
+            Err(MapError::Remap)
+        ));
+
+        // changing a single page inside of the megapage breaks it up
+        let inside = vaddr + GIGA * PAGE_SIZE + 5 * PAGE_SIZE;
+        mapper.protect(inside, Perms::READ, 1).unwrap();
+        assert_eq!(
+            mapper.translate(inside).unwrap().1.bits(),
+            Perms::READ.bits()
+        );
+        assert_eq!(
+            mapper.translate(inside + PAGE_SIZE).unwrap().1.bits(),
+            Perms::READ_WRITE.bits()
+        );
+
+        // and so does unmapping a part of the gigapage
+        mapper.unmap(vaddr + PAGE_SIZE, 1).unwrap();
+        assert!(mapper.translate(vaddr + PAGE_SIZE).is_none());
+        assert_eq!(mapper.translate(vaddr).unwrap().0, paddr);
+
+        mapper.unmap(vaddr, 1).unwrap();
+        mapper
+            .unmap(vaddr + 2 * PAGE_SIZE, GIGA + MEGA - 1)
+            .unwrap();
+        assert!(mapper.translate(probe).is_none());
+
+        assert_eq!(FRAME_ALLOC.lock().free_pages(), free_before);
+        FRAME_ALLOC.lock().free(root).unwrap();
+    }
+}
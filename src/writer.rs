@@ -1,9 +1,15 @@
 use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 
-use crate::riscv::sbi;
+use crate::drivers::uart::CharDriver;
+use crate::riscv::sbi::{self, Extension, SbiError};
 
 static WRITER: spin::Mutex<Writer> = spin::Mutex::new(Writer);
+/// Where output goes, a [Console]. We start out assuming DBCN, until the SBI has been probed
+static CONSOLE: AtomicU8 = AtomicU8::new(Console::Dbcn as u8);
 static LOGGER: WriterLogger = WriterLogger;
+/// Set once the kernel cannot continue, see [set_fatal]
+static FATAL: AtomicBool = AtomicBool::new(false);
 
 // colours for pretty printing
 pub const RESET: &str = "\x1b[0m";
@@ -13,14 +19,81 @@ pub const BRIGHT_MAGENTA: &str = "\x1b[95m";
 
 pub struct Writer;
 pub struct WriterLogger;
+/// Writes through the SBI without taking any lock, for when [WRITER] is stuck
+struct FatalWriter;
+
+/// The backends output can go to. On QEMU all of them end up on the same serial port, so output
+/// does not move when we switch between them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Console {
+    /// the SBI debug console, writes whole strings at once
+    Dbcn,
+    /// the legacy SBI `console_putchar`, one trap per byte
+    Legacy,
+    /// the ns16550a [CharDriver], once it has been initialised
+    Uart,
+}
+
+impl Console {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Dbcn,
+            1 => Self::Legacy,
+            _ => Self::Uart,
+        }
+    }
+}
 
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        sbi::dbcn::write(s);
+        write_lines(s, write_raw);
+        Ok(())
+    }
+}
+
+impl fmt::Write for FatalWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_lines(s, |s| {
+            if console() == Console::Legacy || sbi::dbcn::write(s).is_err() {
+                s.bytes().for_each(sbi::legacy::console_putchar);
+            }
+        });
         Ok(())
     }
 }
 
+/// Hand `s` to `write` a line at a time. Terminals want a carriage return before moving to the
+/// next line, whatever the backend
+fn write_lines(s: &str, mut write: impl FnMut(&str)) {
+    for (i, line) in s.split('\n').enumerate() {
+        if i != 0 {
+            write("\r\n");
+        }
+        if !line.is_empty() {
+            write(line);
+        }
+    }
+}
+
+/// Write `s` to the current console as is
+fn write_raw(s: &str) {
+    match console() {
+        Console::Uart if CharDriver::write_str(s) => return,
+        Console::Dbcn | Console::Uart => match sbi::dbcn::write(s) {
+            Ok(()) => return,
+            // the firmware has no DBCN after all, do not try it again
+            Err(SbiError::NotSupported) => set_console(Console::Legacy),
+            // the string is not mapped where the SBI can reach it, write it byte by byte
+            Err(_) => {}
+        },
+        Console::Legacy => {}
+    }
+
+    // there is nowhere else to report errors to, so this cannot fail
+    s.bytes().for_each(sbi::legacy::console_putchar);
+}
+
 impl WriterLogger {
     const RESET: &str = "\x1b[0m";
     const RED: &str = "\x1b[31m";
@@ -46,9 +119,14 @@ impl log::Log for WriterLogger {
             log::Level::Trace => Self::MAGENTA,
         };
 
+        // seconds since the machine came up, zero until the timebase is known
+        let time = crate::time::Instant::now().since_boot();
+
         crate::println!(
-            "{}{}: {}{}",
+            "{}[{:5}.{:06}] {}: {}{}",
             colour,
+            time.as_secs(),
+            time.subsec_micros(),
             record.level(),
             record.args(),
             Self::RESET
@@ -66,6 +144,36 @@ pub fn init_log() {
         .expect("could not enable logger");
 }
 
+/// The console output goes to
+pub fn console() -> Console {
+    Console::from_u8(CONSOLE.load(Ordering::Relaxed))
+}
+
+/// Send all output to `console` from here on
+pub fn set_console(console: Console) {
+    CONSOLE.store(console as u8, Ordering::Relaxed);
+}
+
+/// The kernel ran into something it cannot continue from. Whoever holds the console lock might
+/// never release it, so from here on printing only tries it, and goes around it through the SBI
+pub fn set_fatal() {
+    FATAL.store(true, Ordering::Release);
+}
+
+/// Pick the best console the SBI has, once its extensions have been probed. The UART takes over
+/// when its driver comes up
+pub fn select_console() {
+    if console() == Console::Uart {
+        return;
+    }
+
+    if sbi::has_extension(Extension::Dbcn) {
+        set_console(Console::Dbcn);
+    } else {
+        set_console(Console::Legacy);
+    }
+}
+
 // pub fn clear_screen() {
 //     const CLEAR_SCREEN: &str = "\x1b[2J\x1b[1;1H";
 //     crate::print!("{CLEAR_SCREEN}");
@@ -74,7 +182,19 @@ pub fn init_log() {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use fmt::Write as _;
-    ::riscv::interrupt::free(|| WRITER.lock().write_fmt(args).unwrap());
+
+    // there is nowhere to report a failed print to, so errors are dropped
+    ::riscv::interrupt::free(|| {
+        if !FATAL.load(Ordering::Acquire) {
+            let _ = WRITER.lock().write_fmt(args);
+            return;
+        }
+
+        let _ = match WRITER.try_lock() {
+            Some(mut writer) => writer.write_fmt(args),
+            None => FatalWriter.write_fmt(args),
+        };
+    });
 }
 
 #[macro_export]
@@ -87,3 +207,27 @@ macro_rules! println {
     () => ($crate::print!("\n"));
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn every_console() {
+        let previous = console();
+
+        set_console(Console::Legacy);
+        crate::println!("[writer::tests::every_console] legacy");
+
+        if sbi::has_extension(Extension::Dbcn) {
+            set_console(Console::Dbcn);
+            crate::println!("[writer::tests::every_console] dbcn");
+            assert_eq!(console(), Console::Dbcn);
+        }
+
+        set_console(Console::Uart);
+        crate::println!("[writer::tests::every_console] uart");
+
+        set_console(previous);
+    }
+}